@@ -14,7 +14,7 @@ impl Plugin for CoolPlugin {
     type HostCallInput = HostInput;
     type HostCallOutput = HostOutput;
 
-    fn new() -> Self {
+    fn new(host: &mut Host<HostInput, HostOutput>) -> Self {
         CoolPlugin {
 
         }
@@ -0,0 +1,55 @@
+//! Measures the host-side cost of moving messages across the plugin boundary: validating
+//! buffer descriptors, deserializing the plugin's message and serializing the reply.
+//!
+//! Run with `cargo bench --features host`. Each line reports the mean time per host call
+//! and the resulting throughput for a payload of the given size.
+
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+
+use plugitin::abi::pack_buffer_desc;
+use plugitin::host::{handle_host_call, HostHandler, MessageLimits};
+
+// Echoes its input back, so the reply is the same size as the request.
+struct Echo;
+
+impl HostHandler for Echo {
+    type Input = Vec<u8>;
+    type Output = Vec<u8>;
+
+    fn handle(&mut self, input: Vec<u8>) -> Vec<u8> {
+        input
+    }
+}
+
+const PAYLOAD_SIZES: [usize; 6] = [0, 64, 1024, 16 * 1024, 256 * 1024, 4 * 1024 * 1024];
+const TARGET_DURATION: Duration = Duration::from_millis(500);
+
+fn main() {
+    let limits = MessageLimits::default();
+    println!("{:>12} {:>14} {:>14}", "payload", "time/call", "throughput");
+
+    for &size in PAYLOAD_SIZES.iter() {
+        // Lay the serialized request out in a fake linear memory at a nonzero offset, the
+        // way a plugin would hand it to plugitin_host_call.
+        let request = bincode::serialize(&vec![0xA5u8; size]).unwrap();
+        let offset = 16;
+        let mut memory = vec![0u8; offset + request.len()];
+        memory[offset..].copy_from_slice(&request);
+        let packed = pack_buffer_desc(offset as u32, request.len() as u32);
+
+        let mut handler = Echo;
+        let mut iterations = 0u64;
+        let start = Instant::now();
+        while start.elapsed() < TARGET_DURATION {
+            let input = limits.read_incoming(black_box(&memory), black_box(packed)).unwrap();
+            let output = handle_host_call(&mut handler, input, &limits).unwrap();
+            black_box(output);
+            iterations += 1;
+        }
+        let per_call = start.elapsed() / iterations as u32;
+        let throughput = (size as f64 * iterations as f64)
+            / start.elapsed().as_secs_f64() / (1024.0 * 1024.0);
+        println!("{:>12} {:>14?} {:>10.1} MiB/s", size, per_call, throughput);
+    }
+}
@@ -0,0 +1,410 @@
+//! The low-level ABI spoken between a plugitin host and a plugitin plugin.
+//!
+//! Rust plugins never need to touch this module directly because the `plugin!` macro
+//! generates all of the required glue. It exists so that plugins written in other
+//! languages (C, Zig, AssemblyScript, ...) can target the same host.
+//!
+//! # Exports
+//! A plugin module must export the following functions. All integers are wasm `i32` or
+//! `i64` values interpreted as unsigned.
+//!
+//! | Name                                           | Signature                                      |
+//! |------------------------------------------------|------------------------------------------------|
+//! | [`plugitin_init`](INIT_EXPORT)                 | `() -> u32`                                    |
+//! | [`plugitin_destroy`](DESTROY_EXPORT)           | `(info: u32)`                                  |
+//! | [`plugitin_alloc`](ALLOC_EXPORT)               | `(info: u32, size: u32, align: u32) -> u32`    |
+//! | [`plugitin_alloc_uninit`](ALLOC_UNINIT_EXPORT) | `(info: u32, size: u32, align: u32) -> u32`    |
+//! | [`plugitin_dealloc`](DEALLOC_EXPORT)           | `(info: u32, ptr: u32, size: u32, align: u32)` |
+//! | [`plugitin_client_call`](CLIENT_CALL_EXPORT)   | `(info: u32, input: u64) -> u64`               |
+//!
+//! Plugins may additionally export
+//! [`plugitin_client_call_batch`](CLIENT_CALL_BATCH_EXPORT), which has the same signature
+//! as `plugitin_client_call` but takes a batch of inputs and returns a batch of outputs,
+//! amortizing the cost of crossing the boundary over many small calls.
+//!
+//! Plugins which schedule timers export [`plugitin_on_timer`](ON_TIMER_EXPORT), with
+//! signature `(info: u32, token: u64)`, which the host calls when a timer elapses.
+//!
+//! Plugins may additionally export the following lifecycle hooks, each with signature
+//! `(info: u32)`. Hosts should call [`plugitin_on_load`](ON_LOAD_EXPORT) right after
+//! `plugitin_init`, [`plugitin_on_idle`](ON_IDLE_EXPORT) once the plugin has not been
+//! called for a host-defined period, and [`plugitin_on_unload`](ON_UNLOAD_EXPORT) right
+//! before `plugitin_destroy`. Hosts must tolerate the hooks being absent.
+//!
+//! Plugins may also export [`plugitin_buffer_sizes`](BUFFER_SIZES_EXPORT), with signature
+//! `(info: u32) -> u64`, which reports the sizes of the plugin's transfer buffers so that
+//! hosts can attribute memory usage. See [`pack_buffer_sizes`] for the result's layout.
+//!
+//! Plugins which consume large immutable datasets export
+//! [`plugitin_set_shared_data`](SET_SHARED_DATA_EXPORT), with signature
+//! `(info: u32, data: u64)`, along with
+//! [`plugitin_alloc_shared_data`](ALLOC_SHARED_DATA_EXPORT), with signature
+//! `(info: u32, size: u32) -> u32`. Once `plugitin_init` has returned, the host allocates
+//! memory for the dataset with `plugitin_alloc_shared_data`, writes the dataset into it
+//! once and passes it to `plugitin_set_shared_data`. Ownership of the memory passes to the
+//! plugin, which reads it in place for the rest of its lifetime instead of receiving the
+//! data with every call. Passing another dataset replaces the previous one. Datasets are
+//! not messages, so they are capped separately from the plugin's message size limit.
+//!
+//! Plugins which read per-call context export
+//! [`plugitin_set_call_context`](SET_CALL_CONTEXT_EXPORT), with signature
+//! `(info: u32, context: u64)`. Hosts call it right before `plugitin_client_call` or
+//! `plugitin_client_call_batch` with a serialized `context::CallContext`, which applies to
+//! that call only. Memory for the context is allocated with `plugitin_alloc_uninit` using
+//! an alignment of 1, and handed over like a shared dataset.
+//!
+//! Plugins may also export [`plugitin_last_error`](LAST_ERROR_EXPORT), with signature
+//! `() -> u32`, which hosts can call after the plugin traps to learn why as one of the
+//! `ERROR_*` codes. This matters for plugins built to trap without a message.
+//!
+//! `info` is the opaque value returned by `plugitin_init`. The host must pass it unchanged
+//! to every other export and must not use it after passing it to `plugitin_destroy`. The
+//! plugin may call `plugitin_host_call` while `plugitin_init` is still running, in which
+//! case the host learns `info` from that call and may use it to allocate and free memory,
+//! but must not call `plugitin_client_call` until `plugitin_init` has returned.
+//!
+//! # Multiple plugins
+//! A module may contain several plugins. Each plugin is given a namespace and exports the
+//! functions above with [`NAMESPACE_SEPARATOR`] and its namespace appended, so a plugin
+//! namespaced `foo` exports `plugitin_init__foo`, `plugitin_client_call__foo` and so on.
+//! See [`namespaced_export`]. Hosts enumerate the plugins in a module by looking for
+//! `plugitin_init` and `plugitin_init__*` exports, and must only pass `info` values
+//! returned by a plugin's init export to that same plugin's exports. Imports are shared by
+//! every plugin in the module.
+//!
+//! # Imports
+//! A plugin module may import the following functions from the [`IMPORT_MODULE`] module.
+//!
+//! | Name                                               | Signature                                   |
+//! |----------------------------------------------------|---------------------------------------------|
+//! | [`plugitin_host_call`](HOST_CALL_IMPORT)           | `(info: u32, input: u64) -> u64`            |
+//! | [`plugitin_host_output`](HOST_OUTPUT_IMPORT)       | `(info: u32, stream: u32, text: u64)`       |
+//! | [`plugitin_host_now`](HOST_NOW_IMPORT)             | `(info: u32) -> u64`                        |
+//! | [`plugitin_host_random`](HOST_RANDOM_IMPORT)       | `(info: u32) -> u64`                        |
+//! | [`plugitin_host_kv_get`](HOST_KV_GET_IMPORT)       | `(info: u32, key: u64, dest: u64) -> u64`   |
+//! | [`plugitin_host_kv_set`](HOST_KV_SET_IMPORT)       | `(info: u32, key: u64, value: u64)`         |
+//! | [`plugitin_host_kv_delete`](HOST_KV_DELETE_IMPORT) | `(info: u32, key: u64) -> u32`              |
+//! | [`plugitin_host_http`](HOST_HTTP_IMPORT)           | `(info: u32, request: u64) -> u64`          |
+//! | [`plugitin_host_schedule`](HOST_SCHEDULE_IMPORT)   | `(info: u32, delay_nanos: u64, token: u64)` |
+//!
+//! # Buffer descriptors
+//! The following `u64` values are packed [`BufferDesc`]s, each describing a region of the
+//! plugin's linear memory. See [`pack_buffer_desc`] for the exact layout.
+//!
+//! - `input` and the result of `plugitin_client_call` and `plugitin_client_call_batch`
+//! - `data` of `plugitin_set_shared_data` and `context` of `plugitin_set_call_context`
+//! - `input` and the result of `plugitin_host_call`, unless the result is
+//!   [`HOST_CALL_REFUSED`]
+//! - `text` of `plugitin_host_output`
+//! - `key`, `dest` and `value` of the `plugitin_host_kv_*` imports
+//! - `request` and the result of `plugitin_host_http`
+//!
+//! All other `u64` values are plain integers: the results of `plugitin_host_now`,
+//! `plugitin_host_random` and `plugitin_host_kv_get`, `delay_nanos` and `token` of
+//! `plugitin_host_schedule`, `token` of `plugitin_on_timer`, and the result of
+//! `plugitin_buffer_sizes`, which packs two sizes as described by [`pack_buffer_sizes`].
+//!
+//! # Versioned envelopes
+//! Plugins and hosts may opt into wrapping client call inputs and outputs in a versioned
+//! envelope, so that peers built against different versions of an interface can detect
+//! the mismatch and migrate messages. An enveloped message is the interface version as a
+//! little-endian `u32`, followed by the serialized payload. See [`split_envelope`] and
+//! [`write_envelope_header`].
+//!
+//! # Memory ownership
+//! `plugitin_alloc` returns zeroed memory, while `plugitin_alloc_uninit` skips zeroing and
+//! should be preferred for transfer buffers the host overwrites entirely. Memory returned
+//! by either is owned by the host until it passes the same `(ptr, size, align)` triple to
+//! `plugitin_dealloc`. Zero-byte requests are allowed and return a pointer which is
+//! aligned but must not be accessed.
+//!
+//! The buffer described by the result of `plugitin_client_call` or
+//! `plugitin_client_call_batch` is owned by the plugin. It is only valid until the host
+//! calls [`plugitin_release_output`](RELEASE_OUTPUT_EXPORT), with signature `(info: u32)`,
+//! or makes the next call into the plugin, whichever comes first. Hosts should release the
+//! output as soon as they have read it, which lets the plugin free large output buffers
+//! instead of holding on to them until the next call.
+//!
+//! The input buffer passed to `plugitin_host_call` or `plugitin_host_http` is owned by the
+//! plugin and only valid for the duration of that call. The buffer described by the result
+//! must have been allocated by the host with `plugitin_alloc` or `plugitin_alloc_uninit`,
+//! with an alignment of 1 and a size equal to the result's length. Ownership of it passes
+//! to the plugin, which frees it once it has read the result, so the host must not touch
+//! it after the import returns. Empty results need not be allocated.
+
+/// Name of the module that plugins import host functions from.
+pub const IMPORT_MODULE: &str = "env";
+
+/// Name of the export which constructs the plugin. Signature: `() -> u32`.
+pub const INIT_EXPORT: &str = "plugitin_init";
+
+/// Name of the export which tears down the plugin. Signature: `(info: u32)`.
+pub const DESTROY_EXPORT: &str = "plugitin_destroy";
+
+/// Name of the export which allocates plugin memory on behalf of the host. Signature:
+/// `(info: u32, size: u32, align: u32) -> u32`.
+pub const ALLOC_EXPORT: &str = "plugitin_alloc";
+
+/// Name of the export which allocates plugin memory on behalf of the host without zeroing
+/// it. Signature: `(info: u32, size: u32, align: u32) -> u32`.
+pub const ALLOC_UNINIT_EXPORT: &str = "plugitin_alloc_uninit";
+
+/// Name of the export which frees memory previously returned by [`ALLOC_EXPORT`] or
+/// [`ALLOC_UNINIT_EXPORT`].
+/// Signature: `(info: u32, ptr: u32, size: u32, align: u32)`.
+pub const DEALLOC_EXPORT: &str = "plugitin_dealloc";
+
+/// Name of the export which allows the host to call the plugin. Signature:
+/// `(info: u32, input: u64) -> u64`.
+pub const CLIENT_CALL_EXPORT: &str = "plugitin_client_call";
+
+/// Name of the optional export which makes several logical calls to the plugin in a
+/// single boundary crossing. Signature: `(info: u32, input: u64) -> u64`. The input is a
+/// serialized `Vec` of client call inputs and the result a serialized `Vec` of the
+/// corresponding outputs.
+pub const CLIENT_CALL_BATCH_EXPORT: &str = "plugitin_client_call_batch";
+
+/// Name of the optional export invoked when a timer scheduled through
+/// [`HOST_SCHEDULE_IMPORT`] elapses. Signature: `(info: u32, token: u64)`.
+pub const ON_TIMER_EXPORT: &str = "plugitin_on_timer";
+
+/// Name of the optional export invoked right after the plugin is initialized. Signature:
+/// `(info: u32)`.
+pub const ON_LOAD_EXPORT: &str = "plugitin_on_load";
+
+/// Name of the optional export invoked when the plugin has been idle for a while.
+/// Signature: `(info: u32)`.
+pub const ON_IDLE_EXPORT: &str = "plugitin_on_idle";
+
+/// Name of the optional export invoked right before the plugin is destroyed. Signature:
+/// `(info: u32)`.
+pub const ON_UNLOAD_EXPORT: &str = "plugitin_on_unload";
+
+/// Name of the optional export through which the host tells the plugin it has finished
+/// reading the output of the last client call. Signature: `(info: u32)`.
+pub const RELEASE_OUTPUT_EXPORT: &str = "plugitin_release_output";
+
+/// Name of the optional export through which the host hands the plugin a read-only
+/// dataset it has written into the plugin's memory. Signature: `(info: u32, data: u64)`.
+pub const SET_SHARED_DATA_EXPORT: &str = "plugitin_set_shared_data";
+
+/// Name of the optional export which allocates memory for a dataset passed to
+/// [`SET_SHARED_DATA_EXPORT`], with an alignment of 1 and without zeroing it. Signature:
+/// `(info: u32, size: u32) -> u32`.
+pub const ALLOC_SHARED_DATA_EXPORT: &str = "plugitin_alloc_shared_data";
+
+/// Name of the optional export through which the host sets the context of the next client
+/// call. Signature: `(info: u32, context: u64)`.
+pub const SET_CALL_CONTEXT_EXPORT: &str = "plugitin_set_call_context";
+
+/// Name of the optional export which reports the sizes of the plugin's transfer buffers.
+/// Signature: `(info: u32) -> u64`. The result is packed by [`pack_buffer_sizes`].
+pub const BUFFER_SIZES_EXPORT: &str = "plugitin_buffer_sizes";
+
+/// Name of the import which allows the plugin to call the host. Signature:
+/// `(info: u32, input: u64) -> u64`. The plugin takes ownership of the memory described by
+/// the result. Hosts which limit how much a plugin may call back into them during a single
+/// client call return [`HOST_CALL_REFUSED`] once the limit is exceeded.
+pub const HOST_CALL_IMPORT: &str = "plugitin_host_call";
+
+/// Name of the import which forwards text written to one of the plugin's output streams
+/// to the host. Signature: `(info: u32, stream: u32, text: u64)`, where `stream` is
+/// [`STDOUT_STREAM`] or [`STDERR_STREAM`] and `text` describes UTF-8 bytes which are only
+/// valid for the duration of the call. Writes may split multi-byte characters.
+pub const HOST_OUTPUT_IMPORT: &str = "plugitin_host_output";
+
+/// Name of the import which returns the host's current time in nanoseconds since the Unix
+/// epoch. Signature: `(info: u32) -> u64`.
+pub const HOST_NOW_IMPORT: &str = "plugitin_host_now";
+
+/// Name of the import which returns 64 random bits from the host. Signature:
+/// `(info: u32) -> u64`.
+pub const HOST_RANDOM_IMPORT: &str = "plugitin_host_random";
+
+/// Name of the import which reads a value from the plugin's key-value storage.
+/// Signature: `(info: u32, key: u64, dest: u64) -> u64`. `key` describes the key and
+/// `dest` a buffer the host writes the value into if it fits. Returns the length of the
+/// value, or [`KV_ABSENT`] if there is none. If the value does not fit, nothing is written
+/// and the plugin may retry with a large enough buffer.
+pub const HOST_KV_GET_IMPORT: &str = "plugitin_host_kv_get";
+
+/// Name of the import which writes a value to the plugin's key-value storage. Signature:
+/// `(info: u32, key: u64, value: u64)`.
+pub const HOST_KV_SET_IMPORT: &str = "plugitin_host_kv_set";
+
+/// Name of the import which removes a value from the plugin's key-value storage.
+/// Signature: `(info: u32, key: u64) -> u32`. Returns 1 if a value was removed and 0
+/// otherwise.
+pub const HOST_KV_DELETE_IMPORT: &str = "plugitin_host_kv_delete";
+
+/// Name of the import which performs an HTTP request on behalf of the plugin. Signature:
+/// `(info: u32, request: u64) -> u64`. Memory for the response is handled like the output
+/// of [`HOST_CALL_IMPORT`]. Requests and responses are serialized with the types in the
+/// `http` module.
+pub const HOST_HTTP_IMPORT: &str = "plugitin_host_http";
+
+/// Name of the import which schedules a timer. Signature:
+/// `(info: u32, delay_nanos: u64, token: u64)`. Once at least `delay_nanos` have elapsed
+/// the host calls [`ON_TIMER_EXPORT`] with `token`.
+pub const HOST_SCHEDULE_IMPORT: &str = "plugitin_host_schedule";
+
+/// Separator between an export name and the namespace of the plugin it belongs to, in
+/// modules which contain several plugins.
+pub const NAMESPACE_SEPARATOR: &str = "__";
+
+/// Returns the name under which the plugin with the given namespace provides `export`. The
+/// empty namespace denotes a module's unnamespaced plugin, whose exports are unsuffixed.
+///
+/// ```
+/// use plugitin::abi::{namespaced_export, INIT_EXPORT};
+///
+/// assert_eq!(namespaced_export(INIT_EXPORT, "foo"), "plugitin_init__foo");
+/// assert_eq!(namespaced_export(INIT_EXPORT, ""), "plugitin_init");
+/// ```
+pub fn namespaced_export(export: &str, namespace: &str) -> String {
+    if namespace.is_empty() {
+        export.to_string()
+    } else {
+        format!("{}{}{}", export, NAMESPACE_SEPARATOR, namespace)
+    }
+}
+
+/// Name of the optional export which reports the reason the plugin last trapped.
+/// Signature: `() -> u32`. Returns one of the `ERROR_*` codes, or [`ERROR_NONE`] if the
+/// plugin has not trapped or trapped for a reason plugitin does not know about, such as a
+/// panic in plugin code.
+pub const LAST_ERROR_EXPORT: &str = "plugitin_last_error";
+
+/// Error code reported when no plugitin error has occurred.
+pub const ERROR_NONE: u32 = 0;
+
+/// Error code reported when the host passed a plugin handle that `plugitin_init` never
+/// returned.
+pub const ERROR_INVALID_HANDLE: u32 = 1;
+
+/// Error code reported when the host passed an invalid size and alignment to an allocation
+/// export.
+pub const ERROR_INVALID_LAYOUT: u32 = 2;
+
+/// Error code reported when the host called the plugin before `plugitin_init` returned.
+pub const ERROR_NOT_INITIALIZED: u32 = 3;
+
+/// Error code reported when a message exceeded the plugin's size limits.
+pub const ERROR_MESSAGE_TOO_LARGE: u32 = 4;
+
+/// Error code reported when a message was too short to contain a versioned envelope.
+pub const ERROR_INVALID_ENVELOPE: u32 = 5;
+
+/// Error code reported when a message had an interface version the plugin cannot handle.
+pub const ERROR_UNSUPPORTED_VERSION: u32 = 6;
+
+/// Error code reported when the plugin failed to migrate a message from an older interface
+/// version.
+pub const ERROR_MIGRATION_FAILED: u32 = 7;
+
+/// Error code reported when the plugin failed to deserialize a message.
+pub const ERROR_DESERIALIZE_FAILED: u32 = 8;
+
+/// Error code reported when the plugin failed to serialize a message.
+pub const ERROR_SERIALIZE_FAILED: u32 = 9;
+
+/// Error code reported when the module had too many live plugin instances.
+pub const ERROR_TOO_MANY_INSTANCES: u32 = 10;
+
+/// Error code reported when the host refused a host call the plugin did not handle.
+pub const ERROR_HOST_CALL_REFUSED: u32 = 11;
+
+/// Error code reported when the host passed the handle of a plugin instance it has already
+/// destroyed.
+pub const ERROR_STALE_HANDLE: u32 = 12;
+
+/// Error code reported when the host passed the handle of one plugin to the exports of
+/// another plugin declared in the same module.
+pub const ERROR_WRONG_PLUGIN: u32 = 13;
+
+/// Returned by [`HOST_CALL_IMPORT`] when the host refuses to handle the call.
+pub const HOST_CALL_REFUSED: u64 = u64::MAX;
+
+/// Returned by [`HOST_KV_GET_IMPORT`] when no value is stored under the key.
+pub const KV_ABSENT: u64 = u64::MAX;
+
+/// Stream identifier for the plugin's standard output.
+pub const STDOUT_STREAM: u32 = 1;
+
+/// Stream identifier for the plugin's standard error.
+pub const STDERR_STREAM: u32 = 2;
+
+/// A (pointer, length) pair describing a region of the plugin's linear memory.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct BufferDesc {
+    pub ptr: u32,
+    pub len: u32,
+}
+
+impl BufferDesc {
+    pub fn new(ptr: u32, len: u32) -> Self {
+        Self { ptr, len }
+    }
+
+    /// Packs this descriptor into the `u64` representation used on the wire.
+    pub fn pack(self) -> u64 {
+        pack_buffer_desc(self.ptr, self.len)
+    }
+
+    /// Unpacks a descriptor from the `u64` representation used on the wire.
+    pub fn unpack(packed: u64) -> Self {
+        let (ptr, len) = unpack_buffer_desc(packed);
+        Self { ptr, len }
+    }
+}
+
+/// WASM can't return tuples yet so this function packs a (pointer, length) pair of u32s
+/// into a single u64 which can be returned as a unit. The pointer is stored in the lower
+/// 32 bits and the length is stored in the higher 32 bits. See unpack_buffer_desc for the
+/// complementary unpacking operation.
+pub fn pack_buffer_desc(ptr: u32, len: u32) -> u64 {
+    (ptr as u64) | ((len as u64) << 32)
+}
+
+/// Unpacks a (pointer, length) pair of u32s representing a buffer descriptor from a
+/// packed u64. The u64 must have been packed by pack_buffer_desc previously.
+pub fn unpack_buffer_desc(packed: u64) -> (u32, u32) {
+    let ptr = packed as u32;
+    let len = (packed >> 32) as u32;
+    (ptr, len)
+}
+
+/// Packs the sizes of a plugin's client call output buffer and host call input buffer into
+/// the `u64` returned by [`BUFFER_SIZES_EXPORT`]. The client call output buffer size is
+/// stored in the lower 32 bits and the host call input buffer size in the higher 32 bits.
+pub fn pack_buffer_sizes(client_call_output: u32, host_call_input: u32) -> u64 {
+    (client_call_output as u64) | ((host_call_input as u64) << 32)
+}
+
+/// Unpacks the (client call output, host call input) buffer sizes packed by
+/// [`pack_buffer_sizes`].
+pub fn unpack_buffer_sizes(packed: u64) -> (u32, u32) {
+    (packed as u32, (packed >> 32) as u32)
+}
+
+/// Length in bytes of the header which prefixes a versioned envelope.
+pub const ENVELOPE_HEADER_LEN: usize = 4;
+
+/// Writes a versioned envelope header to the start of `buffer`, which must be at least
+/// [`ENVELOPE_HEADER_LEN`] bytes long. The payload follows immediately after the header.
+pub fn write_envelope_header(buffer: &mut [u8], version: u32) {
+    buffer[..ENVELOPE_HEADER_LEN].copy_from_slice(&version.to_le_bytes());
+}
+
+/// Splits a versioned envelope into its interface version and payload. Returns None if the
+/// message is too short to contain an envelope header.
+pub fn split_envelope(message: &[u8]) -> Option<(u32, &[u8])> {
+    if message.len() < ENVELOPE_HEADER_LEN {
+        return None;
+    }
+    let (header, payload) = message.split_at(ENVELOPE_HEADER_LEN);
+    let mut version = [0u8; ENVELOPE_HEADER_LEN];
+    version.copy_from_slice(header);
+    Some((u32::from_le_bytes(version), payload))
+}
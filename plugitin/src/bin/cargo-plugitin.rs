@@ -0,0 +1,219 @@
+//! Scaffolding for new plugitin plugins.
+//!
+//! Usage: `cargo plugitin new <name> [--path <dir> | --git <url>]` (or `cargo-plugitin`)
+//!
+//! Creates a `<name>` directory containing a cargo workspace with two crates: a
+//! `<name>_interface` crate holding the message types shared between the plugin and its
+//! host, and a `<name>` cdylib crate implementing the plugin with the `plugin!` macro. The
+//! workspace is configured to build for wasm32-unknown-unknown with a size-tuned release
+//! profile.
+//!
+//! The plugin depends on plugitin through `--path` or `--git` if given. Otherwise it
+//! depends on the plugitin sources this tool was built from, so that the generated code
+//! matches the API it was written against.
+
+use std::fs;
+use std::path::Path;
+use std::process::exit;
+
+fn main() {
+    let mut args: Vec<String> = std::env::args().skip(1).collect();
+    // When run as `cargo plugitin`, cargo passes the subcommand name as the first argument.
+    if args.first().map(String::as_str) == Some("plugitin") {
+        args.remove(0);
+    }
+
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+    let (name, source) = match args.as_slice() {
+        ["new", name] => (name, Source::Path(env!("CARGO_MANIFEST_DIR").to_string())),
+        ["new", name, "--path", path] => match fs::canonicalize(path) {
+            Ok(path) => (name, Source::Path(path.display().to_string())),
+            Err(err) => {
+                eprintln!("error: failed to resolve {}: {}", path, err);
+                exit(1);
+            }
+        },
+        ["new", name, "--git", url] => (name, Source::Git(url.to_string())),
+        _ => {
+            eprintln!("usage: cargo plugitin new <name> [--path <dir> | --git <url>]");
+            exit(2);
+        }
+    };
+    if let Err(err) = new_plugin(name, &source) {
+        eprintln!("error: {}", err);
+        exit(1);
+    }
+    println!("Created plugin `{}`. Build it with `cargo build --release`.", name);
+}
+
+/// Where the generated plugin gets plugitin from.
+enum Source {
+    Path(String),
+    Git(String),
+}
+
+impl Source {
+    // Renders the source as the keys of a cargo dependency table.
+    fn dependency(&self) -> String {
+        match self {
+            Source::Path(path) => format!("path = {}", toml_string(path)),
+            Source::Git(url) => format!("git = {}", toml_string(url)),
+        }
+    }
+}
+
+fn toml_string(value: &str) -> String {
+    format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+fn new_plugin(name: &str, source: &Source) -> Result<(), String> {
+    let valid_name = name.starts_with(|c: char| c.is_ascii_alphabetic())
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+    if !valid_name {
+        return Err(format!(
+            "`{}` is not a valid plugin name; use letters, digits and underscores", name));
+    }
+
+    let root = Path::new(name);
+    if root.exists() {
+        return Err(format!("destination `{}` already exists", root.display()));
+    }
+
+    let interface = format!("{}_interface", name);
+    let type_name = camel_case(name);
+    let plugitin = source.dependency();
+    let replace = |template: &str| template
+        .replace("{name}", name)
+        .replace("{interface}", &interface)
+        .replace("{type_name}", &type_name)
+        .replace("{plugitin}", &plugitin);
+
+    let files = [
+        ("Cargo.toml", WORKSPACE_MANIFEST),
+        (".cargo/config.toml", CARGO_CONFIG),
+        (".gitignore", GITIGNORE),
+        ("{interface}/Cargo.toml", INTERFACE_MANIFEST),
+        ("{interface}/src/lib.rs", INTERFACE_LIB),
+        ("{name}/Cargo.toml", PLUGIN_MANIFEST),
+        ("{name}/src/lib.rs", PLUGIN_LIB),
+    ];
+    for (path, contents) in files.iter() {
+        let path = root.join(replace(path));
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)
+                .map_err(|err| format!("failed to create {}: {}", parent.display(), err))?;
+        }
+        fs::write(&path, replace(contents))
+            .map_err(|err| format!("failed to write {}: {}", path.display(), err))?;
+    }
+    Ok(())
+}
+
+fn camel_case(name: &str) -> String {
+    name.split('_')
+        .filter(|word| !word.is_empty())
+        .map(|word| {
+            let mut chars = word.chars();
+            let first = chars.next().unwrap().to_ascii_uppercase();
+            std::iter::once(first).chain(chars).collect::<String>()
+        })
+        .collect()
+}
+
+const WORKSPACE_MANIFEST: &str = r#"[workspace]
+members = ["{interface}", "{name}"]
+
+# Plugins are shipped as .wasm files, so optimize release builds for size.
+[profile.release]
+opt-level = "s"
+lto = true
+codegen-units = 1
+panic = "abort"
+"#;
+
+const CARGO_CONFIG: &str = r#"[build]
+target = "wasm32-unknown-unknown"
+"#;
+
+const GITIGNORE: &str = "target/\n";
+
+const INTERFACE_MANIFEST: &str = r#"[package]
+name = "{interface}"
+version = "0.1.0"
+edition = "2018"
+
+[dependencies]
+serde = { version = "1.0", features = ["derive"] }
+"#;
+
+const INTERFACE_LIB: &str = r#"//! Message types shared between the {name} plugin and its host.
+
+use serde::{Deserialize, Serialize};
+
+/// Sent by the host when it calls the plugin.
+#[derive(Debug, Deserialize, Serialize)]
+pub enum ClientInput {
+    Ping,
+}
+
+/// Returned by the plugin to the host.
+#[derive(Debug, Deserialize, Serialize)]
+pub enum ClientOutput {
+    Pong,
+}
+
+/// Sent by the plugin when it calls the host.
+#[derive(Debug, Deserialize, Serialize)]
+pub enum HostInput {
+    Ping,
+}
+
+/// Returned by the host to the plugin.
+#[derive(Debug, Deserialize, Serialize)]
+pub enum HostOutput {
+    Pong,
+}
+"#;
+
+const PLUGIN_MANIFEST: &str = r#"[package]
+name = "{name}"
+version = "0.1.0"
+edition = "2018"
+
+[lib]
+crate-type = ["cdylib"]
+
+[dependencies]
+plugitin = { {plugitin}, features = ["client"] }
+{interface} = { path = "../{interface}" }
+"#;
+
+const PLUGIN_LIB: &str = r#"use plugitin::plugin;
+use plugitin::client::{Host, Plugin};
+use {interface}::{ClientInput, ClientOutput, HostInput, HostOutput};
+
+plugin!({type_name});
+
+struct {type_name};
+
+impl Plugin for {type_name} {
+    type ClientCallInput = ClientInput;
+    type ClientCallOutput = ClientOutput;
+    type HostCallInput = HostInput;
+    type HostCallOutput = HostOutput;
+
+    fn new(_host: &mut Host<HostInput, HostOutput>) -> Self {
+        {type_name}
+    }
+
+    fn call(&mut self, input: &ClientInput, host: &mut Host<HostInput, HostOutput>)
+        -> ClientOutput
+    {
+        match input {
+            ClientInput::Ping => match host.call(HostInput::Ping) {
+                HostOutput::Pong => ClientOutput::Pong,
+            },
+        }
+    }
+}
+"#;
@@ -0,0 +1,88 @@
+//! Validates compiled plugin binaries against the plugitin ABI.
+//!
+//! Usage: `plugitin-inspect <plugin.wasm>...`
+//!
+//! Prints the imports, exports, plugins and custom sections of each module along with any
+//! ABI problems found, and exits with a nonzero status if any module has problems.
+
+use std::process::exit;
+
+use plugitin::inspect::{check_module, parse_module};
+
+fn main() {
+    let paths: Vec<String> = std::env::args().skip(1).collect();
+    if paths.is_empty() {
+        eprintln!("usage: plugitin-inspect <plugin.wasm>...");
+        exit(2);
+    }
+
+    let mut failed = false;
+    for path in paths.iter() {
+        if !inspect(path) {
+            failed = true;
+        }
+    }
+    if failed {
+        exit(1);
+    }
+}
+
+// Prints a report for a single module. Returns whether the module is a valid plugin.
+fn inspect(path: &str) -> bool {
+    println!("{}:", path);
+
+    let bytes = match std::fs::read(path) {
+        Ok(bytes) => bytes,
+        Err(err) => {
+            println!("  error: {}", err);
+            return false;
+        }
+    };
+    let info = match parse_module(&bytes) {
+        Ok(info) => info,
+        Err(err) => {
+            println!("  error: {}", err);
+            return false;
+        }
+    };
+
+    println!("  imports:");
+    for import in info.imports.iter() {
+        println!("    {}::{}: {}", import.module, import.name, import.kind);
+    }
+    println!("  exports:");
+    for export in info.exports.iter() {
+        println!("    {}: {}", export.name, export.kind);
+    }
+    match info.manifest() {
+        Some(Ok(manifest)) => {
+            println!("  manifest:");
+            println!("    name: {}", manifest.name);
+            println!("    version: {}", manifest.version);
+            println!("    capabilities: {}", manifest.capabilities.join(", "));
+        }
+        Some(Err(err)) => println!("  manifest error: {}", err),
+        None => {}
+    }
+    println!("  plugins:");
+    for namespace in info.plugins() {
+        if namespace.is_empty() {
+            println!("    (unnamespaced)");
+        } else {
+            println!("    {}", namespace);
+        }
+    }
+    println!("  custom sections:");
+    for section in info.custom_sections.iter() {
+        println!("    {} ({} bytes)", section.name, section.data.len());
+    }
+
+    let problems = check_module(&info);
+    if problems.is_empty() {
+        println!("  ok");
+    }
+    for problem in problems.iter() {
+        println!("  problem: {}", problem);
+    }
+    problems.is_empty()
+}
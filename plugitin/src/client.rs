@@ -4,15 +4,39 @@
 //! This module is only available if the **client** feature is enabled.
 
 use std::alloc::Layout;
-use std::marker::PhantomData;
-use std::mem::size_of;
+use std::any::TypeId;
+use std::convert::TryFrom;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Mutex, MutexGuard};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
-use crate::{pack_buffer_desc, unpack_buffer_desc};
+use crate::abi::{
+    pack_buffer_desc, pack_buffer_sizes, split_envelope, unpack_buffer_desc,
+    write_envelope_header, BufferDesc, ENVELOPE_HEADER_LEN, ERROR_DESERIALIZE_FAILED,
+    ERROR_HOST_CALL_REFUSED, ERROR_INVALID_ENVELOPE, ERROR_INVALID_HANDLE,
+    ERROR_INVALID_LAYOUT, ERROR_MESSAGE_TOO_LARGE, ERROR_MIGRATION_FAILED, ERROR_NONE,
+    ERROR_NOT_INITIALIZED, ERROR_SERIALIZE_FAILED, ERROR_STALE_HANDLE,
+    ERROR_TOO_MANY_INSTANCES, ERROR_UNSUPPORTED_VERSION, ERROR_WRONG_PLUGIN, HOST_CALL_REFUSED,
+    KV_ABSENT, STDERR_STREAM, STDOUT_STREAM,
+};
+use crate::codec::deserialize_message;
+use crate::context::{CallContext, WireContext};
+use crate::http::{
+    response_from_wire, HttpError, HttpRequest, HttpResponse, WireRequest, WireResponse,
+};
+use crate::PlugitinError;
 
-use bincode::{deserialize_from, serialize_into, serialized_size};
+use bincode::{serialize_into, serialized_size};
 use serde::{Deserialize, Serialize};
 
-/// Declares a client plugin. Takes a single argument, the name of the plugin type.
+/// Declares a client plugin. Takes the plugin type, optionally followed by `=` and an
+/// expression which constructs the plugin. Without a constructor expression the plugin is
+/// constructed with `Plugin::new`, which is given access to the host. The plugin type must
+/// be `'static`.
+///
+/// Several plugins can be declared in one module by giving each a namespace, as in
+/// `plugin!(foo: FooPlugin, bar: BarPlugin = BarPlugin::default())`. Each plugin's exports
+/// are then suffixed with `__` and its namespace, for example `plugitin_init__foo`.
 ///
 /// # Features
 /// Only available if the **client** feature is enabled.
@@ -20,145 +44,786 @@ use serde::{Deserialize, Serialize};
 /// # Examples
 ///
 /// ```
+/// use plugitin::plugin;
+/// use plugitin::client::{Host, Plugin};
+///
 /// plugin!(MyPlugin);
 ///
 /// struct MyPlugin;
 ///
 /// impl Plugin for MyPlugin {
-///     fn new() -> Self {
+///     type ClientCallInput = ();
+///     type ClientCallOutput = ();
+///     type HostCallInput = ();
+///     type HostCallOutput = ();
+///
+///     fn new(host: &mut Host<(), ()>) -> Self {
 ///         MyPlugin {}
 ///     }
+///
+///     fn call(&mut self, input: &(), host: &mut Host<(), ()>) {}
+/// }
+/// ```
+///
+/// Generic plugin types and custom constructors are supported too.
+///
+/// ```
+/// use plugitin::plugin;
+/// use plugitin::client::{Host, Plugin};
+///
+/// plugin!(MyPlugin<Config> = MyPlugin::with_config(Config { verbose: true }));
+///
+/// struct Config {
+///     verbose: bool,
+/// }
+///
+/// struct MyPlugin<C> {
+///     config: Option<C>,
+/// }
+///
+/// impl<C> MyPlugin<C> {
+///     fn with_config(config: C) -> Self {
+///         MyPlugin { config: Some(config) }
+///     }
 /// }
+///
+/// impl<C> Plugin for MyPlugin<C> {
+///     type ClientCallInput = ();
+///     type ClientCallOutput = ();
+///     type HostCallInput = ();
+///     type HostCallOutput = ();
+///
+///     fn new(host: &mut Host<(), ()>) -> Self {
+///         MyPlugin { config: None }
+///     }
+///
+///     fn call(&mut self, input: &(), host: &mut Host<(), ()>) {}
+/// }
+/// ```
+///
+/// Multiple namespaced plugins in one module:
+///
+/// ```
+/// use plugitin::plugin;
+/// use plugitin::client::{Host, Plugin};
+///
+/// plugin!(upper: Upper, lower: Lower = Lower);
+///
+/// struct Upper;
+/// struct Lower;
+///
+/// impl Plugin for Upper {
+///     type ClientCallInput = String;
+///     type ClientCallOutput = String;
+///     type HostCallInput = ();
+///     type HostCallOutput = ();
+///
+///     fn new(host: &mut Host<(), ()>) -> Self {
+///         Upper
+///     }
+///
+///     fn call(&mut self, input: &String, host: &mut Host<(), ()>) -> String {
+///         input.to_uppercase()
+///     }
+/// }
+///
+/// impl Plugin for Lower {
+///     type ClientCallInput = String;
+///     type ClientCallOutput = String;
+///     type HostCallInput = ();
+///     type HostCallOutput = ();
+///
+///     fn new(host: &mut Host<(), ()>) -> Self {
+///         Lower
+///     }
+///
+///     fn call(&mut self, input: &String, host: &mut Host<(), ()>) -> String {
+///         input.to_lowercase()
+///     }
+/// }
+/// ```
 #[macro_export]
 macro_rules! plugin {
-    ($name:ty) => {
-        #[no_mangle]
-        fn plugitin_init() -> u32 {
-            $crate::client::plugitin_init_impl::<$name>()
-        }
+    (@exports $suffix:expr, $name:ty, $init:expr) => {
+        const _: () = {
+            #[export_name = concat!("plugitin_init", $suffix)]
+            fn plugitin_init() -> u32 {
+                $crate::client::plugitin_init_impl::<$name, _>($init)
+            }
 
-        #[no_mangle]
-        fn plugitin_destroy(info: u32) {
-            $crate::client::plugitin_destroy_impl::<$name>(info)
-        }
+            #[export_name = concat!("plugitin_destroy", $suffix)]
+            fn plugitin_destroy(info: u32) {
+                $crate::client::plugitin_destroy_impl::<$name>(info)
+            }
 
-        #[no_mangle]
-        fn plugitin_alloc(info: u32, size: u32, align: u32) -> u32 {
-            $crate::client::plugitin_alloc_impl::<$name>(info, size, align)
-        }
+            #[export_name = concat!("plugitin_alloc", $suffix)]
+            fn plugitin_alloc(info: u32, size: u32, align: u32) -> u32 {
+                $crate::client::plugitin_alloc_impl::<$name>(info, size, align)
+            }
 
-        #[no_mangle]
-        fn plugitin_dealloc(info: u32, ptr: u32, size: u32, align: u32) {
-            $crate::client::plugitin_dealloc_impl::<$name>(info, ptr, size, align)
-        }
+            #[export_name = concat!("plugitin_alloc_uninit", $suffix)]
+            fn plugitin_alloc_uninit(info: u32, size: u32, align: u32) -> u32 {
+                $crate::client::plugitin_alloc_uninit_impl::<$name>(info, size, align)
+            }
 
-        #[no_mangle]
-        fn plugitin_client_call(info: u32, input_packed: u64) -> u64 {
-            $crate::client::plugitin_client_call_impl::<$name>(info, input_packed)
-        }
-    }
+            #[export_name = concat!("plugitin_dealloc", $suffix)]
+            fn plugitin_dealloc(info: u32, ptr: u32, size: u32, align: u32) {
+                $crate::client::plugitin_dealloc_impl::<$name>(info, ptr, size, align)
+            }
+
+            #[export_name = concat!("plugitin_client_call", $suffix)]
+            fn plugitin_client_call(info: u32, input_packed: u64) -> u64 {
+                $crate::client::plugitin_client_call_impl::<$name>(info, input_packed)
+            }
+
+            #[export_name = concat!("plugitin_client_call_batch", $suffix)]
+            fn plugitin_client_call_batch(info: u32, input_packed: u64) -> u64 {
+                $crate::client::plugitin_client_call_batch_impl::<$name>(info, input_packed)
+            }
+
+            #[export_name = concat!("plugitin_on_timer", $suffix)]
+            fn plugitin_on_timer(info: u32, token: u64) {
+                $crate::client::plugitin_on_timer_impl::<$name>(info, token)
+            }
+
+            #[export_name = concat!("plugitin_on_load", $suffix)]
+            fn plugitin_on_load(info: u32) {
+                $crate::client::plugitin_on_load_impl::<$name>(info)
+            }
+
+            #[export_name = concat!("plugitin_on_idle", $suffix)]
+            fn plugitin_on_idle(info: u32) {
+                $crate::client::plugitin_on_idle_impl::<$name>(info)
+            }
+
+            #[export_name = concat!("plugitin_on_unload", $suffix)]
+            fn plugitin_on_unload(info: u32) {
+                $crate::client::plugitin_on_unload_impl::<$name>(info)
+            }
+
+            #[export_name = concat!("plugitin_release_output", $suffix)]
+            fn plugitin_release_output(info: u32) {
+                $crate::client::plugitin_release_output_impl::<$name>(info)
+            }
+
+            #[export_name = concat!("plugitin_alloc_shared_data", $suffix)]
+            fn plugitin_alloc_shared_data(info: u32, size: u32) -> u32 {
+                $crate::client::plugitin_alloc_shared_data_impl::<$name>(info, size)
+            }
+
+            #[export_name = concat!("plugitin_set_shared_data", $suffix)]
+            fn plugitin_set_shared_data(info: u32, data_packed: u64) {
+                $crate::client::plugitin_set_shared_data_impl::<$name>(info, data_packed)
+            }
+
+            #[export_name = concat!("plugitin_set_call_context", $suffix)]
+            fn plugitin_set_call_context(info: u32, context_packed: u64) {
+                $crate::client::plugitin_set_call_context_impl::<$name>(info, context_packed)
+            }
+
+            #[export_name = concat!("plugitin_last_error", $suffix)]
+            fn plugitin_last_error() -> u32 {
+                $crate::client::plugitin_last_error_impl()
+            }
+
+            #[export_name = concat!("plugitin_buffer_sizes", $suffix)]
+            fn plugitin_buffer_sizes(info: u32) -> u64 {
+                $crate::client::plugitin_buffer_sizes_impl::<$name>(info)
+            }
+        };
+    };
+    (@namespaced $namespace:ident : $name:ty) => {
+        $crate::plugin!(
+            @exports concat!("__", stringify!($namespace)),
+            $name,
+            |host| <$name as $crate::client::Plugin>::new(host));
+    };
+    (@namespaced $namespace:ident : $name:ty = $constructor:expr) => {
+        $crate::plugin!(
+            @exports concat!("__", stringify!($namespace)), $name, |_| $constructor);
+    };
+    ($name:ty) => {
+        $crate::plugin!(@exports "", $name, |host| <$name as $crate::client::Plugin>::new(host));
+    };
+    ($name:ty = $constructor:expr) => {
+        $crate::plugin!(@exports "", $name, |_| $constructor);
+    };
+    ($($namespace:ident : $name:ty $(= $constructor:expr)?),+ $(,)?) => {
+        $( $crate::plugin!(@namespaced $namespace : $name $(= $constructor)?); )+
+    };
 }
 
-// Entry point to the plugin. Returns an opaque data pointer which will be passed
-// unchanged as an argument to all further plugin calls.
+// Entry point to the plugin. Constructs the plugin with the given function and returns an
+// opaque handle which will be passed unchanged as an argument to all further plugin calls.
+// The handle is issued before the plugin is constructed so that the constructor can call
+// the host, which in turn may need to allocate memory in the plugin.
 #[doc(hidden)]
-pub fn plugitin_init_impl<P: Plugin>() -> u32 {
+pub fn plugitin_init_impl<P, F>(constructor: F) -> u32
+    where P: Plugin + 'static, F: FnOnce(&mut Host<P::HostCallInput, P::HostCallOutput>) -> P
+{
     // It is impossible to know up front the maximum serialized size that input/outputs
     // will take, due to the possibility of types arbitrarily amplifying their serialized
     // sizes (see https://github.com/servo/bincode/issues/291). Therefore we need to
     // support buffer resizing. I set the initial size of the buffers to 0 so that
     // resizing logic is always invoked, giving less space for bugs to hide in resizing
     // code that might otherwise be infrequently called.
-    Box::into_raw(Box::new(PluginInfo {
-        plugin: P::new(),
+    let info = Box::into_raw(Box::new(PluginInfo::<P> {
+        plugin: None,
         client_call_output_buffer: vec![0u8; 0].into_boxed_slice(),
-        host_call_input_buffer: vec![0u8; 0].into_boxed_slice(),
-    })) as u32
+        host: HostState {
+            host_call_input_buffer: vec![0u8; 0].into_boxed_slice(),
+            layers: Vec::new(),
+            shared_data: BufferDesc::default(),
+            call_context: CallContext::new(),
+        },
+    }));
+    let handle = insert_handle::<P>(info as usize);
+
+    let info_ref = info_ref::<P>(handle);
+    let mut host = Host::new(
+        handle,
+        &mut info_ref.host,
+        P::dealloc,
+        P::MAX_OUTGOING_MESSAGE_SIZE,
+        P::MAX_INCOMING_MESSAGE_SIZE,
+        P::DESERIALIZE_LIMIT);
+    let plugin = constructor(&mut host);
+    info_ref.plugin = Some(plugin);
+    handle
 }
 
-// Called to tear down the plugin. Input is the exact same opaque data pointer
-// previously retrieved from plugin_init.
+// Called to tear down the plugin. Input is the exact same opaque handle previously
+// retrieved from plugin_init. The handle is invalidated, so any further use of it is
+// reported as a stale handle rather than touching freed memory. A constructor which calls
+// the host hands it the handle before plugin_init returns, so destroy is refused until
+// then, since the constructor is still using the PluginInfo.
 #[doc(hidden)]
-pub fn plugitin_destroy_impl<P: Plugin>(info: u32) {
-    unsafe { Box::from_raw(info as *mut PluginInfo<P>); }
+pub fn plugitin_destroy_impl<P: Plugin + 'static>(info: u32) {
+    info_ref::<P>(info).plugin.as_ref()
+        .or_fail(ERROR_NOT_INITIALIZED, "Host destroyed the plugin before plugitin_init returned");
+    let ptr = remove_handle::<P>(info)
+        .unwrap_or_else(|err| fail(err.code(), err));
+    let info = unsafe { Box::from_raw(ptr as *mut PluginInfo<P>) };
+    free_host_output(P::dealloc, info.host.shared_data.ptr, info.host.shared_data.len);
 }
 
 // Called to allocate memory so that the host can pass data to the plugin.
 #[doc(hidden)]
-pub fn plugitin_alloc_impl<P: Plugin>(info: u32, size: u32, align: u32) -> u32 {
-    let info_ref = info_ref::<P>(info);
+pub fn plugitin_alloc_impl<P: Plugin + 'static>(info: u32, size: u32, align: u32) -> u32 {
+    lookup_handle::<P>(info)
+        .unwrap_or_else(|err| fail(err.code(), err));
+    // Everything the host sends us arrives through memory allocated here, so this is the
+    // point where incoming message sizes are capped.
+    check_message_size(size as u64, P::MAX_INCOMING_MESSAGE_SIZE);
     let layout = std::alloc::Layout::from_size_align(size as usize, align as usize)
-        .expect("Invalid layout parameters");
-    info_ref.plugin.alloc(layout) as u32
+        .or_fail(ERROR_INVALID_LAYOUT, "Invalid layout parameters");
+    allocate(layout, P::alloc)
 }
 
-// Called to deallocate memory that was previously allocated by plugitin_alloc.
+// Like plugitin_alloc, but the memory is not zeroed. Used for transfer buffers which the
+// host overwrites entirely before handing them back.
 #[doc(hidden)]
-pub fn plugitin_dealloc_impl<P: Plugin>(info: u32, ptr: u32, size: u32, align: u32) {
-    let info_ref = info_ref::<P>(info);
+pub fn plugitin_alloc_uninit_impl<P: Plugin + 'static>(info: u32, size: u32, align: u32) -> u32 {
+    lookup_handle::<P>(info)
+        .unwrap_or_else(|err| fail(err.code(), err));
+    check_message_size(size as u64, P::MAX_INCOMING_MESSAGE_SIZE);
     let layout = std::alloc::Layout::from_size_align(size as usize, align as usize)
-        .expect("Invalid layout parameters");
-    let ptr = ptr as *mut u8;
-    info_ref.plugin.dealloc(ptr, layout);
+        .or_fail(ERROR_INVALID_LAYOUT, "Invalid layout parameters");
+    allocate(layout, P::alloc_uninit)
+}
+
+// Called to deallocate memory that was previously allocated by plugitin_alloc or
+// plugitin_alloc_uninit.
+#[doc(hidden)]
+pub fn plugitin_dealloc_impl<P: Plugin + 'static>(info: u32, ptr: u32, size: u32, align: u32) {
+    lookup_handle::<P>(info)
+        .unwrap_or_else(|err| fail(err.code(), err));
+    let layout = std::alloc::Layout::from_size_align(size as usize, align as usize)
+        .or_fail(ERROR_INVALID_LAYOUT, "Invalid layout parameters");
+    // Zero-sized allocations are never passed to the allocator; see allocate.
+    if layout.size() != 0 {
+        P::dealloc(ptr as *mut u8, layout);
+    }
+}
+
+// Allocates memory for the host with one of the plugin's allocation functions. Allocating
+// zero bytes is undefined behavior for Rust allocators, so zero-sized requests, which the
+// ABI permits, are instead answered with a dangling but suitably aligned pointer.
+fn allocate(layout: Layout, alloc: fn(Layout) -> *mut u8) -> u32 {
+    if layout.size() == 0 {
+        return layout.align() as u32;
+    }
+    alloc(layout) as u32
 }
 
 // Allows the host to call the client.
 #[doc(hidden)]
-pub fn plugitin_client_call_impl<P: Plugin>(info: u32, input_packed: u64) -> u64 {
+pub fn plugitin_client_call_impl<P: Plugin + 'static>(info: u32, input_packed: u64) -> u64 {
     let info_ref = info_ref::<P>(info);
+    let plugin = info_ref.plugin.as_mut()
+        .or_fail(ERROR_NOT_INITIALIZED, "Host called the plugin before plugitin_init returned");
 
     // Read input.
-    let (input_ptr, input_len) = unpack_buffer_desc(input_packed);
-    let input_slice: &[u8] = unsafe {
-        std::slice::from_raw_parts(input_ptr as *const u8, input_len as usize)
+    let input_slice = client_call_input::<P>(input_packed);
+    let call_input: P::ClientCallInput = match P::INTERFACE_VERSION {
+        None => deserialize_message(input_slice, P::DESERIALIZE_LIMIT)
+            .or_fail(ERROR_DESERIALIZE_FAILED, "Failed to deserialize client call input"),
+        Some(version) => {
+            let (input_version, payload) = split_envelope(input_slice)
+                .unwrap_or_else(|| fail(ERROR_INVALID_ENVELOPE, PlugitinError::InvalidEnvelope));
+            if input_version == version {
+                deserialize_message(payload, P::DESERIALIZE_LIMIT)
+                    .or_fail(ERROR_DESERIALIZE_FAILED, "Failed to deserialize client call input")
+            } else {
+                plugin.migrate_input(input_version, payload)
+                    .unwrap_or_else(|err| fail(ERROR_MIGRATION_FAILED, err))
+            }
+        }
     };
-    let call_input: P::ClientCallInput = deserialize_from(input_slice)
-        .expect("Failed to deserialize client call input");
 
     // Call plugin logic.
-    let mut host = Host::new(info, &mut info_ref.host_call_input_buffer);
-    let call_output = info_ref.plugin.call(&call_input, &mut host);
+    let mut host = Host::new(
+        info,
+        &mut info_ref.host,
+        P::dealloc,
+        P::MAX_OUTGOING_MESSAGE_SIZE,
+        P::MAX_INCOMING_MESSAGE_SIZE,
+        P::DESERIALIZE_LIMIT);
+    let call_output = plugin.call(&call_input, &mut host);
+    info_ref.host.call_context = CallContext::new();
+
+    write_client_call_output::<P, _>(&mut info_ref.client_call_output_buffer, &call_output)
+}
 
+// Allows the host to make several logical calls to the client in a single boundary
+// crossing. The input is a serialized sequence of client call inputs and the output is
+// the sequence of corresponding outputs, in the same order. Versioned batches must match
+// the plugin's interface version exactly, because individual inputs cannot be migrated.
+#[doc(hidden)]
+pub fn plugitin_client_call_batch_impl<P: Plugin + 'static>(info: u32, input_packed: u64) -> u64 {
+    let info_ref = info_ref::<P>(info);
+    let plugin = info_ref.plugin.as_mut()
+        .or_fail(ERROR_NOT_INITIALIZED, "Host called the plugin before plugitin_init returned");
+
+    // Read input.
+    let mut input_slice = client_call_input::<P>(input_packed);
+    if let Some(version) = P::INTERFACE_VERSION {
+        let (input_version, payload) = split_envelope(input_slice)
+            .unwrap_or_else(|| fail(ERROR_INVALID_ENVELOPE, PlugitinError::InvalidEnvelope));
+        if input_version != version {
+            let err = PlugitinError::UnsupportedVersion {
+                found: input_version,
+                expected: version,
+            };
+            fail(ERROR_UNSUPPORTED_VERSION, err);
+        }
+        input_slice = payload;
+    }
+    let call_inputs: Vec<P::ClientCallInput> =
+        deserialize_message(input_slice, P::DESERIALIZE_LIMIT)
+            .or_fail(ERROR_DESERIALIZE_FAILED, "Failed to deserialize client call batch input");
+
+    // Call plugin logic once per input.
+    let mut host = Host::new(
+        info,
+        &mut info_ref.host,
+        P::dealloc,
+        P::MAX_OUTGOING_MESSAGE_SIZE,
+        P::MAX_INCOMING_MESSAGE_SIZE,
+        P::DESERIALIZE_LIMIT);
+    let call_outputs: Vec<P::ClientCallOutput> = call_inputs.iter()
+        .map(|call_input| plugin.call(call_input, &mut host))
+        .collect();
+    info_ref.host.call_context = CallContext::new();
+
+    write_client_call_output::<P, _>(&mut info_ref.client_call_output_buffer, &call_outputs)
+}
+
+// Borrows the client call input described by a packed descriptor.
+fn client_call_input<'a, P: Plugin + 'static>(input_packed: u64) -> &'a [u8] {
+    let (input_ptr, input_len) = unpack_buffer_desc(input_packed);
+    check_message_size(input_len as u64, P::MAX_INCOMING_MESSAGE_SIZE);
+    unsafe {
+        std::slice::from_raw_parts(input_ptr as *const u8, input_len as usize)
+    }
+}
+
+// Serializes a client call output into the output buffer, wrapping it in a versioned
+// envelope if the plugin uses one, and returns the packed descriptor of the result.
+fn write_client_call_output<P: Plugin + 'static, T: Serialize>(buffer: &mut Box<[u8]>, output: &T)
+    -> u64
+{
     // Determine whether we need to expand the output buffer.
-    let output_len = serialized_size(&call_output)
-        .expect("Failed to compute serialized size for client call output");
+    let header_len = if P::INTERFACE_VERSION.is_some() { ENVELOPE_HEADER_LEN } else { 0 };
+    let output_len = serialized_size(output)
+        .or_fail(ERROR_SERIALIZE_FAILED, "Failed to compute serialized size for client call output")
+        + header_len as u64;
+    check_message_size(output_len, P::MAX_OUTGOING_MESSAGE_SIZE);
 
-    if output_len as usize > info_ref.client_call_output_buffer.len() {
+    if output_len as usize > buffer.len() {
         let new_buffer = vec![0u8; output_len as usize].into_boxed_slice();
         // Free the old buffer and replace it with the new.
-        let _ = std::mem::replace(&mut info_ref.client_call_output_buffer, new_buffer);
+        let _ = std::mem::replace(buffer, new_buffer);
     }
 
-    let output_slice: &mut [u8] = &mut info_ref.client_call_output_buffer;
-    serialize_into(output_slice, &call_output)
-        .expect("Failed to serialize client call output");
+    let output_slice: &mut [u8] = buffer;
+    if let Some(version) = P::INTERFACE_VERSION {
+        write_envelope_header(output_slice, version);
+    }
+    serialize_into(&mut output_slice[header_len..], output)
+        .or_fail(ERROR_SERIALIZE_FAILED, "Failed to serialize client call output");
 
-    let output_ptr = info_ref.client_call_output_buffer.as_mut_ptr() as u32;
+    let output_ptr = buffer.as_mut_ptr() as u32;
     pack_buffer_desc(output_ptr, output_len as u32)
 }
 
-struct PluginInfo<T> {
-    plugin: T,
-    // The client is responsible for writing to these buffers, so it owns them so that it
-    // can enlarge them when necessary. The host will own the other two buffers that it
-    // is responsible for writing to.
+// Called by the host when a timer scheduled with Host::schedule elapses.
+#[doc(hidden)]
+pub fn plugitin_on_timer_impl<P: Plugin + 'static>(info: u32, token: u64) {
+    let info_ref = info_ref::<P>(info);
+    let plugin = info_ref.plugin.as_mut()
+        .or_fail(ERROR_NOT_INITIALIZED, "Host called the plugin before plugitin_init returned");
+    let mut host = Host::new(
+        info,
+        &mut info_ref.host,
+        P::dealloc,
+        P::MAX_OUTGOING_MESSAGE_SIZE,
+        P::MAX_INCOMING_MESSAGE_SIZE,
+        P::DESERIALIZE_LIMIT);
+    plugin.on_timer(token, &mut host);
+}
+
+// Called by the host right after instantiating the plugin.
+#[doc(hidden)]
+pub fn plugitin_on_load_impl<P: Plugin + 'static>(info: u32) {
+    run_lifecycle_hook::<P>(info, P::on_load)
+}
+
+// Called by the host when the plugin has been idle for longer than the host's threshold.
+#[doc(hidden)]
+pub fn plugitin_on_idle_impl<P: Plugin + 'static>(info: u32) {
+    run_lifecycle_hook::<P>(info, P::on_idle)
+}
+
+// Called by the host right before it destroys the plugin.
+#[doc(hidden)]
+pub fn plugitin_on_unload_impl<P: Plugin + 'static>(info: u32) {
+    run_lifecycle_hook::<P>(info, P::on_unload)
+}
+
+// Called by the host once it has read the output of the last client call. Small output
+// buffers are kept for reuse by the next call, while large ones are freed so that a single
+// large output does not pin memory for the lifetime of the plugin.
+#[doc(hidden)]
+pub fn plugitin_release_output_impl<P: Plugin + 'static>(info: u32) {
+    let info_ref = info_ref::<P>(info);
+    if info_ref.client_call_output_buffer.len() > RETAINED_OUTPUT_BUFFER_SIZE {
+        info_ref.client_call_output_buffer = Box::new([]);
+    }
+}
+
+// Largest client call output buffer kept around after the host releases the output.
+const RETAINED_OUTPUT_BUFFER_SIZE: usize = 64 * 1024;
+
+// Called to allocate memory for a dataset the host is about to pass to
+// plugitin_set_shared_data. Datasets are capped by MAX_SHARED_DATA_SIZE rather than the
+// message size limit, since they are often far larger than any message.
+#[doc(hidden)]
+pub fn plugitin_alloc_shared_data_impl<P: Plugin + 'static>(info: u32, size: u32) -> u32 {
+    lookup_handle::<P>(info)
+        .unwrap_or_else(|err| fail(err.code(), err));
+    check_message_size(size as u64, P::MAX_SHARED_DATA_SIZE);
+    let layout = std::alloc::Layout::from_size_align(size as usize, 1)
+        .or_fail(ERROR_INVALID_LAYOUT, "Invalid layout parameters");
+    allocate(layout, P::alloc_uninit)
+}
+
+// Called by the host to hand over a read-only dataset it wrote into memory allocated with
+// plugitin_alloc_shared_data. The plugin owns the memory from here on and frees it when
+// the dataset is replaced or the plugin is destroyed.
+#[doc(hidden)]
+pub fn plugitin_set_shared_data_impl<P: Plugin + 'static>(info: u32, data_packed: u64) {
+    let info_ref = info_ref::<P>(info);
+    let data = BufferDesc::unpack(data_packed);
+    check_message_size(data.len as u64, P::MAX_SHARED_DATA_SIZE);
+    let previous = std::mem::replace(&mut info_ref.host.shared_data, data);
+    free_host_output(P::dealloc, previous.ptr, previous.len);
+}
+
+// Called by the host right before a client call to set that call's context. Like the
+// output of a host call, the plugin takes ownership of the memory holding the context.
+#[doc(hidden)]
+pub fn plugitin_set_call_context_impl<P: Plugin + 'static>(info: u32, context_packed: u64) {
+    let info_ref = info_ref::<P>(info);
+    let (context_ptr, context_len) = unpack_buffer_desc(context_packed);
+    check_message_size(context_len as u64, P::MAX_INCOMING_MESSAGE_SIZE);
+    let context_slice: &[u8] = unsafe {
+        std::slice::from_raw_parts(context_ptr as *const u8, context_len as usize)
+    };
+    let context: WireContext = deserialize_message(context_slice, P::DESERIALIZE_LIMIT)
+        .or_fail(ERROR_DESERIALIZE_FAILED, "Failed to deserialize call context");
+    free_host_output(P::dealloc, context_ptr, context_len);
+    info_ref.host.call_context = CallContext::from_wire(context);
+}
+
+// Called by the host to find out how much memory the plugin's transfer buffers occupy.
+#[doc(hidden)]
+pub fn plugitin_buffer_sizes_impl<P: Plugin + 'static>(info: u32) -> u64 {
+    let info_ref = info_ref::<P>(info);
+    pack_buffer_sizes(
+        info_ref.client_call_output_buffer.len() as u32,
+        info_ref.host.host_call_input_buffer.len() as u32)
+}
+
+fn run_lifecycle_hook<P: Plugin + 'static>(
+    info: u32,
+    hook: fn(&mut P, &mut Host<P::HostCallInput, P::HostCallOutput>))
+{
+    let info_ref = info_ref::<P>(info);
+    let plugin = info_ref.plugin.as_mut()
+        .or_fail(ERROR_NOT_INITIALIZED, "Host called the plugin before plugitin_init returned");
+    let mut host = Host::new(
+        info,
+        &mut info_ref.host,
+        P::dealloc,
+        P::MAX_OUTGOING_MESSAGE_SIZE,
+        P::MAX_INCOMING_MESSAGE_SIZE,
+        P::DESERIALIZE_LIMIT);
+    hook(plugin, &mut host);
+}
+
+// Size of the buffer Host::kv_get offers the host on its first attempt.
+const KV_INITIAL_VALUE_CAPACITY: usize = 256;
+
+// Traps if a message exceeds the configured size limit. Called before any memory for the
+// message is allocated.
+fn check_message_size(size: u64, limit: u32) {
+    if size > limit as u64 {
+        let err = PlugitinError::MessageTooLarge { size, limit: limit as u64 };
+        fail(ERROR_MESSAGE_TOO_LARGE, err);
+    }
+}
+
+// Code of the error which last made the plugin trap, reported by plugitin_last_error.
+static LAST_ERROR: AtomicU32 = AtomicU32::new(ERROR_NONE);
+
+// Called by the host after the plugin traps to find out why.
+#[doc(hidden)]
+pub fn plugitin_last_error_impl() -> u32 {
+    LAST_ERROR.load(Ordering::Relaxed)
+}
+
+// Traps because of an unrecoverable error, after recording its code for
+// plugitin_last_error. With the tiny feature the detail is discarded instead of formatted,
+// so that the panic formatting machinery is not linked into the plugin.
+#[cold]
+fn fail<D: std::fmt::Display>(code: u32, detail: D) -> ! {
+    LAST_ERROR.store(code, Ordering::Relaxed);
+    #[cfg(feature = "tiny")]
+    {
+        let _ = detail;
+        std::process::abort()
+    }
+    #[cfg(not(feature = "tiny"))]
+    panic!("{}", detail)
+}
+
+// Like expect, but traps through fail.
+trait OrFail<T> {
+    fn or_fail(self, code: u32, message: &str) -> T;
+}
+
+impl<T> OrFail<T> for Option<T> {
+    fn or_fail(self, code: u32, message: &str) -> T {
+        match self {
+            Some(value) => value,
+            None => fail(code, message),
+        }
+    }
+}
+
+impl<T, E: std::fmt::Display> OrFail<T> for Result<T, E> {
+    fn or_fail(self, code: u32, message: &str) -> T {
+        match self {
+            Ok(value) => value,
+            Err(err) => fail(code, format_args!("{}: {}", message, err)),
+        }
+    }
+}
+
+struct PluginInfo<T: Plugin> {
+    // None while the plugin's constructor is running.
+    plugin: Option<T>,
+    // The plugin writes client call outputs and host call inputs, so it owns the buffers
+    // for them and can enlarge them when necessary. Client call inputs are allocated by the
+    // host, which frees them itself, while host call results are allocated by the host and
+    // handed over to the plugin, which frees them once read. See the memory ownership
+    // section of the abi module.
     client_call_output_buffer: Box<[u8]>,
+    host: HostState<T::HostCallInput, T::HostCallOutput>,
+}
+
+// The parts of a plugin instance's state which its Host works with.
+struct HostState<In, Out> {
     host_call_input_buffer: Box<[u8]>,
+    // Installed through Host::add_layer, outermost first.
+    layers: HostCallLayers<In, Out>,
+    // Read-only dataset handed over by the host, owned by the plugin.
+    shared_data: BufferDesc,
+    // Context of the current client call. Reset once the call returns.
+    call_context: CallContext,
+}
+
+type HostCallLayers<In, Out> = Vec<Box<dyn HostCallLayer<In, Out>>>;
+
+fn info_ref<'info, P: Plugin + 'static>(info: u32) -> &'info mut PluginInfo<P> {
+    let ptr = lookup_handle::<P>(info)
+        .unwrap_or_else(|err| fail(err.code(), err));
+    unsafe { &mut *(ptr as *mut PluginInfo<P>) }
+}
+
+// Why a plugin handle passed by the host does not refer to a live instance of the plugin
+// whose export was called. Reported to the host through plugitin_last_error.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum HandleError {
+    // The handle's slot index was never handed out by plugitin_init.
+    Unknown(u32),
+    // The handle referred to a plugin instance which has since been destroyed.
+    Stale(u32),
+    // The handle refers to an instance of a different plugin declared in the same module.
+    WrongPlugin(u32),
+}
+
+impl HandleError {
+    fn code(&self) -> u32 {
+        match self {
+            HandleError::Unknown(_) => ERROR_INVALID_HANDLE,
+            HandleError::Stale(_) => ERROR_STALE_HANDLE,
+            HandleError::WrongPlugin(_) => ERROR_WRONG_PLUGIN,
+        }
+    }
+}
+
+impl std::fmt::Display for HandleError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            HandleError::Unknown(handle) => write!(f, "unknown plugin handle {:#010x}", handle),
+            HandleError::Stale(handle) => write!(f, "stale plugin handle {:#010x}", handle),
+            HandleError::WrongPlugin(handle) => write!(
+                f, "plugin handle {:#010x} belongs to a different plugin", handle),
+        }
+    }
+}
+
+impl std::error::Error for HandleError {}
+
+// Plugin handles are slot-map style: the lower 16 bits index into HANDLE_SLOTS and the
+// upper 16 bits hold the generation of that slot at the time the handle was issued.
+// Destroying a plugin bumps the slot's generation, so old handles stop validating even
+// after the slot is reused. Generations start at 1 so that a zero handle is never valid.
+// Rather than letting a generation wrap around, which would make old handles valid again,
+// a slot whose generation is exhausted is retired by setting its generation to 0 and is
+// never reused.
+const HANDLE_INDEX_BITS: u32 = 16;
+const HANDLE_INDEX_MASK: u32 = (1 << HANDLE_INDEX_BITS) - 1;
+
+struct HandleSlot {
+    generation: u16,
+    // Type of the plugin the slot was issued for. A module may declare several plugins, so
+    // this guards against passing one plugin's handle to another's exports, which would
+    // otherwise reinterpret one PluginInfo type as another.
+    plugin_type: TypeId,
+    // Type-erased pointer to the boxed PluginInfo, or None if the slot is free.
+    info: Option<usize>,
+}
+
+static HANDLE_SLOTS: Mutex<Vec<HandleSlot>> = Mutex::new(Vec::new());
+
+fn handle_slots() -> MutexGuard<'static, Vec<HandleSlot>> {
+    HANDLE_SLOTS.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+fn make_handle(index: usize, generation: u16) -> u32 {
+    (index as u32) | ((generation as u32) << HANDLE_INDEX_BITS)
+}
+
+fn insert_handle<P: 'static>(info: usize) -> u32 {
+    let plugin_type = TypeId::of::<P>();
+    let mut slots = handle_slots();
+    let free = slots.iter().position(|slot| slot.info.is_none() && slot.generation != 0);
+    if let Some(index) = free {
+        slots[index].info = Some(info);
+        slots[index].plugin_type = plugin_type;
+        return make_handle(index, slots[index].generation);
+    }
+    let index = slots.len();
+    if index as u32 > HANDLE_INDEX_MASK {
+        fail(ERROR_TOO_MANY_INSTANCES, "Too many live plugin instances");
+    }
+    slots.push(HandleSlot { generation: 1, plugin_type, info: Some(info) });
+    make_handle(index, 1)
+}
+
+fn lookup_handle<P: 'static>(handle: u32) -> Result<usize, HandleError> {
+    let slots = handle_slots();
+    let index = (handle & HANDLE_INDEX_MASK) as usize;
+    let generation = (handle >> HANDLE_INDEX_BITS) as u16;
+    let slot = slots.get(index).ok_or(HandleError::Unknown(handle))?;
+    match slot.info {
+        Some(_) if slot.generation != generation => Err(HandleError::Stale(handle)),
+        Some(_) if slot.plugin_type != TypeId::of::<P>() => {
+            Err(HandleError::WrongPlugin(handle))
+        }
+        Some(info) => Ok(info),
+        None => Err(HandleError::Stale(handle)),
+    }
 }
 
-fn info_ref<'info, P>(info: u32) -> &'info mut PluginInfo<P> {
-    unsafe { (info as *mut PluginInfo<P>).as_mut() }
-        .expect("Host provided a plugin pointer that is null")
+fn remove_handle<P: 'static>(handle: u32) -> Result<usize, HandleError> {
+    let info = lookup_handle::<P>(handle)?;
+    let mut slots = handle_slots();
+    let slot = &mut slots[(handle & HANDLE_INDEX_MASK) as usize];
+    slot.info = None;
+    slot.generation = slot.generation.checked_add(1).unwrap_or(0);
+    Ok(info)
 }
 
 extern "C" {
-    // Allows the host to call the client. input_ptr and input_len describe a memory
-    // location in the plugin's linear memory that the plugin wrote the serialized input
-    // data to. output_ptr is where the plugin expects the host to write the
-    // serialized output to. The return value is how many bytes the host wrote to
-    // output_ptr. The size of allocated memory at output_ptr is guaranteed to equal
-    // the size returned by plugitin_buffer_max.
+    // Allows the plugin to call the host. input_buffer describes the serialized input in
+    // the plugin's linear memory, which is only valid for the duration of the call. The
+    // result describes the serialized output, which the host wrote into memory it
+    // allocated with plugitin_alloc or plugitin_alloc_uninit with an alignment of 1. The
+    // plugin owns that memory once the call returns and frees it with free_host_output.
+    // The result is abi::HOST_CALL_REFUSED instead if the host refused the call.
     fn plugitin_host_call(plugin: u32, input_buffer: u64) -> u64;
+
+    // Forwards text written to one of the plugin's output streams to the host. stream is
+    // one of the abi::*_STREAM constants and text describes UTF-8 bytes in the plugin's
+    // linear memory which are only valid for the duration of the call.
+    fn plugitin_host_output(plugin: u32, stream: u32, text: u64);
+
+    // Returns the host's current time in nanoseconds since the Unix epoch.
+    fn plugitin_host_now(plugin: u32) -> u64;
+
+    // Returns 64 random bits from the host.
+    fn plugitin_host_random(plugin: u32) -> u64;
+
+    // Looks up key in the plugin's key-value namespace. If the value fits in dest it is
+    // written there. Returns the value's length, or abi::KV_ABSENT if there is no value.
+    fn plugitin_host_kv_get(plugin: u32, key: u64, dest: u64) -> u64;
+
+    // Stores value under key in the plugin's key-value namespace.
+    fn plugitin_host_kv_set(plugin: u32, key: u64, value: u64);
+
+    // Removes key from the plugin's key-value namespace. Returns 1 if it was present.
+    fn plugitin_host_kv_delete(plugin: u32, key: u64) -> u32;
+
+    // Performs a serialized HTTP request on behalf of the plugin. Returns the serialized
+    // response in memory the host allocated with plugitin_alloc, like plugitin_host_call.
+    fn plugitin_host_http(plugin: u32, request: u64) -> u64;
+
+    // Asks the host to invoke plugitin_on_timer with token once delay_nanos have elapsed.
+    fn plugitin_host_schedule(plugin: u32, delay_nanos: u64, token: u64);
 }
 
 /// Main trait which plugins must implement.
@@ -168,23 +833,105 @@ pub trait Plugin {
     type HostCallInput    : Serialize;
     type HostCallOutput   : for<'de> Deserialize<'de>;
 
-    /// Initialize a new plugin.
-    fn new() -> Self;
+    /// Largest message, in bytes, that the host may send to this plugin. Requests from the
+    /// host to allocate more than this for a message cause the plugin to trap before any
+    /// memory is allocated.
+    const MAX_INCOMING_MESSAGE_SIZE: u32 = u32::MAX;
+
+    /// Largest serialized message, in bytes, that this plugin will send to the host.
+    /// Exceeding it causes the plugin to trap before the transfer buffer is enlarged.
+    const MAX_OUTGOING_MESSAGE_SIZE: u32 = u32::MAX;
+
+    /// Largest dataset, in bytes, that the host may share with this plugin through
+    /// `Host::shared_data`. Kept separate from `MAX_INCOMING_MESSAGE_SIZE` so that plugins
+    /// can cap messages tightly and still receive large datasets such as model weights.
+    const MAX_SHARED_DATA_SIZE: u32 = u32::MAX;
+
+    /// Version of the host/plugin interface this plugin implements. If set, client call
+    /// inputs and outputs are wrapped in a versioned envelope (see the `abi` module), and
+    /// inputs from other interface versions are passed to `migrate_input`. Host calls are
+    /// not enveloped.
+    const INTERFACE_VERSION: Option<u32> = None;
 
-    /// Allocates memory. Necessary so that the host can obtain memory to write to. The
-    /// default implementation passes through to the standard Rust allocator. If you
-    /// override the default implementation, make sure to also override `dealloc`.
-    fn alloc(&mut self, layout: Layout) -> *mut u8 {
+    /// Maximum number of bytes bincode may consume while deserializing a single message
+    /// from the host. Deserialization is always limited to the length of the received
+    /// message, so that a hostile host cannot use inflated length prefixes to force huge
+    /// allocations; setting this lowers the limit further.
+    const DESERIALIZE_LIMIT: Option<u64> = None;
+
+    /// Initialize a new plugin. The host may be called while initializing, for example to
+    /// fetch configuration.
+    fn new(host: &mut Host<Self::HostCallInput, Self::HostCallOutput>) -> Self;
+
+    /// Allocates memory. Necessary so that the host can obtain memory to write to. Not
+    /// tied to a plugin instance because the host may need to allocate while the plugin is
+    /// still being constructed. The default implementation passes through to the standard
+    /// Rust allocator. If you override the default implementation, make sure to also
+    /// override `dealloc`. Never called with a zero-sized layout.
+    fn alloc(layout: Layout) -> *mut u8 {
         unsafe { std::alloc::alloc_zeroed(layout) }
     }
 
-    /// Deallocates memory. The default implementation passes through to the standard Rust
-    /// allocator. If you override the default implementation, make sure to also override
-    /// `alloc`.
-    fn dealloc(&mut self, ptr: *mut u8, layout: Layout) {
+    /// Allocates memory without zeroing it. Used for transfer buffers which the host
+    /// immediately overwrites. The default implementation passes through to the standard
+    /// Rust allocator. Memory returned from here is freed with `dealloc`, so if you
+    /// override `dealloc` make sure to also override this. Never called with a zero-sized
+    /// layout.
+    fn alloc_uninit(layout: Layout) -> *mut u8 {
+        unsafe { std::alloc::alloc(layout) }
+    }
+
+    /// Deallocates memory returned by `alloc` or `alloc_uninit`. The default implementation
+    /// passes through to the standard Rust allocator. If you override the default
+    /// implementation, make sure to also override `alloc`.
+    #[allow(clippy::not_unsafe_ptr_arg_deref)]
+    fn dealloc(ptr: *mut u8, layout: Layout) {
         unsafe { std::alloc::dealloc(ptr, layout) }
     }
 
+    /// Converts a client call input produced for a different interface version into this
+    /// plugin's input type. `payload` is the serialized input with the envelope removed.
+    /// Only invoked if `INTERFACE_VERSION` is set. The default implementation rejects all
+    /// other versions, which causes the plugin to trap.
+    fn migrate_input(&mut self, version: u32, payload: &[u8])
+        -> Result<Self::ClientCallInput, PlugitinError>
+    {
+        let _ = payload;
+        Err(PlugitinError::UnsupportedVersion {
+            found: version,
+            expected: Self::INTERFACE_VERSION.unwrap_or_default(),
+        })
+    }
+
+    /// Invoked by the host right after the plugin has been instantiated and initialized,
+    /// for example to warm caches. The default implementation does nothing.
+    fn on_load(&mut self, host: &mut Host<Self::HostCallInput, Self::HostCallOutput>) {
+        let _ = host;
+    }
+
+    /// Invoked by the host when the plugin has not been called for longer than a
+    /// host-defined threshold, for example to flush buffered state. The default
+    /// implementation does nothing.
+    fn on_idle(&mut self, host: &mut Host<Self::HostCallInput, Self::HostCallOutput>) {
+        let _ = host;
+    }
+
+    /// Invoked by the host right before the plugin is torn down. The default
+    /// implementation does nothing.
+    fn on_unload(&mut self, host: &mut Host<Self::HostCallInput, Self::HostCallOutput>) {
+        let _ = host;
+    }
+
+    /// Invoked by the host when a timer scheduled with `Host::schedule` elapses. `token` is
+    /// the value passed when scheduling. The default implementation does nothing.
+    fn on_timer(
+        &mut self,
+        token: u64,
+        host: &mut Host<Self::HostCallInput, Self::HostCallOutput>)
+    {
+        let _ = (token, host);
+    }
+
     /// Invoked when the host calls the client.
     fn call(
         &mut self,
@@ -193,48 +940,553 @@ pub trait Plugin {
         -> Self::ClientCallOutput;
 }
 
+/// A layer around `Host::call` and `Host::try_call`, installed with `Host::add_layer`.
+/// Layers can inspect or rewrite inputs and outputs, answer calls without involving the
+/// host, for example from a cache, or attach context such as tracing IDs to every call.
+pub trait HostCallLayer<In, Out> {
+    /// Handles a host call. `next` passes an input on to the next layer, or to the host if
+    /// this is the innermost layer, and may be called any number of times.
+    fn call(&mut self, input: In, next: &mut HostCallNext<In, Out>)
+        -> Result<Out, PlugitinError>;
+}
+
+/// The rest of the layer chain, as seen by a `HostCallLayer`.
+pub type HostCallNext<'a, In, Out> = dyn FnMut(In) -> Result<Out, PlugitinError> + 'a;
+
+impl<In, Out, F> HostCallLayer<In, Out> for F
+    where F: FnMut(In, &mut HostCallNext<In, Out>) -> Result<Out, PlugitinError>
+{
+    fn call(&mut self, input: In, next: &mut HostCallNext<In, Out>)
+        -> Result<Out, PlugitinError>
+    {
+        self(input, next)
+    }
+}
+
+fn call_through_layers<In, Out>(
+    layers: &mut [Box<dyn HostCallLayer<In, Out>>],
+    call_host: &mut HostCallNext<In, Out>,
+    input: In)
+    -> Result<Out, PlugitinError>
+{
+    match layers.split_first_mut() {
+        None => call_host(input),
+        Some((layer, inner)) => {
+            layer.call(input, &mut |input| call_through_layers(inner, call_host, input))
+        }
+    }
+}
+
+// Serializes a host call input into the transfer buffer, invokes the host and deserializes
+// its output. Fails only if the host refuses the call.
+fn call_host<In, Out>(
+    info: u32,
+    host_call_input_buffer: &mut Box<[u8]>,
+    dealloc: fn(*mut u8, Layout),
+    max_message_size: u32,
+    deserialize_limit: Option<u64>,
+    input: &In)
+    -> Result<Out, PlugitinError>
+    where In: Serialize, for<'de> Out: Deserialize<'de>
+{
+    // Determine whether we need to expand the input buffer.
+    let input_len : usize = serialized_size(input)
+        .or_fail(ERROR_SERIALIZE_FAILED, "Failed to compute serialized size for host call input")
+        as usize;
+    check_message_size(input_len as u64, max_message_size);
+
+    if input_len > host_call_input_buffer.len() {
+        let new_buffer = vec![0u8; input_len].into_boxed_slice();
+        // Free the old buffer and replace it with the new.
+        let _ = std::mem::replace(host_call_input_buffer, new_buffer);
+    }
+
+    // Serialize into host's input.
+    let input_slice: &mut [u8] = host_call_input_buffer;
+    serialize_into(input_slice, input)
+        .or_fail(ERROR_SERIALIZE_FAILED, "Failed to serialize host call input");
+
+    let input_ptr = host_call_input_buffer.as_mut_ptr() as u32;
+    let input_packed = pack_buffer_desc(input_ptr, input_len as u32);
+
+    // Invoke the host.
+    let output_packed = unsafe { plugitin_host_call(info, input_packed) };
+    if output_packed == HOST_CALL_REFUSED {
+        return Err(PlugitinError::HostCallRefused);
+    }
+    let (output_ptr, output_len) = unpack_buffer_desc(output_packed);
+
+    // Deserialize from host's output, which we own and free once it has been read.
+    let output_slice: &[u8] = unsafe {
+        std::slice::from_raw_parts(output_ptr as *mut u8, output_len as usize)
+    };
+    let output = deserialize_message(output_slice, deserialize_limit)
+        .or_fail(ERROR_DESERIALIZE_FAILED, "Failed to deserialize host call output");
+    free_host_output(dealloc, output_ptr, output_len);
+    Ok(output)
+}
+
+// Frees a buffer the host allocated to hand an output to the plugin.
+fn free_host_output(dealloc: fn(*mut u8, Layout), ptr: u32, len: u32) {
+    if len != 0 {
+        let layout = Layout::from_size_align(len as usize, 1)
+            .or_fail(ERROR_INVALID_LAYOUT, "Invalid layout parameters");
+        dealloc(ptr as *mut u8, layout);
+    }
+}
+
 pub struct Host<'info, In, Out> {
     info: u32,
-    host_call_input_buffer: &'info mut Box<[u8]>,
-    _types: PhantomData<(In, Out)>
+    state: &'info mut HostState<In, Out>,
+    // The plugin's Plugin::dealloc, used to free outputs the host allocated.
+    dealloc: fn(*mut u8, Layout),
+    max_message_size: u32,
+    // The plugin's Plugin::MAX_INCOMING_MESSAGE_SIZE, which caps values the plugin reads
+    // directly into memory of its own, such as key-value values.
+    max_incoming_message_size: u32,
+    deserialize_limit: Option<u64>,
 }
 
 impl<'info, In, Out> Host<'info, In, Out> where In : Serialize, for<'de> Out : Deserialize<'de> {
-    fn new(info: u32, host_call_input_buffer: &'info mut Box<[u8]>) -> Self {
+    fn new(
+        info: u32,
+        state: &'info mut HostState<In, Out>,
+        dealloc: fn(*mut u8, Layout),
+        max_message_size: u32,
+        max_incoming_message_size: u32,
+        deserialize_limit: Option<u64>)
+        -> Self
+    {
         Self {
             info,
-            host_call_input_buffer,
-            _types: PhantomData
+            state,
+            dealloc,
+            max_message_size,
+            max_incoming_message_size,
+            deserialize_limit,
         }
     }
 
+    /// Calls the host, passing through every installed `HostCallLayer` on the way. Traps
+    /// if the host refuses the call; use `try_call` to handle that instead.
     pub fn call(&mut self, input: In) -> Out {
-        // Determine whether we need to expand the input buffer.
-        let input_len : usize = serialized_size(&input)
-            .expect("Failed to compute serialized size for host call input") as usize;
+        self.try_call(input).unwrap_or_else(|err| fail(ERROR_HOST_CALL_REFUSED, err))
+    }
 
-        if input_len > self.host_call_input_buffer.len() {
-            let new_buffer = vec![0u8; input_len].into_boxed_slice();
-            // Free the old buffer and replace it with the new.
-            let _ = std::mem::replace(self.host_call_input_buffer, new_buffer);
+    /// Like `call`, but returns `PlugitinError::HostCallRefused` if the host refuses the
+    /// call, for example because the plugin used up the host calls or bytes it may send
+    /// while handling a single client call.
+    pub fn try_call(&mut self, input: In) -> Result<Out, PlugitinError> {
+        let info = self.info;
+        let buffer = &mut self.state.host_call_input_buffer;
+        let dealloc = self.dealloc;
+        let max_message_size = self.max_message_size;
+        let deserialize_limit = self.deserialize_limit;
+        let mut call_host = |input: In| {
+            call_host(info, buffer, dealloc, max_message_size, deserialize_limit, &input)
+        };
+        call_through_layers(&mut self.state.layers, &mut call_host, input)
+    }
+
+    /// Installs a layer around `call`, typically from `Plugin::new`. Layers stay installed
+    /// for the lifetime of the plugin. The first layer installed is the outermost, so it
+    /// sees inputs first and outputs last.
+    ///
+    /// ```
+    /// use std::collections::HashMap;
+    /// use plugitin::PlugitinError;
+    /// use plugitin::client::{Host, HostCallLayer, HostCallNext, Plugin};
+    ///
+    /// // Answers repeated lookups without crossing the boundary again.
+    /// #[derive(Default)]
+    /// struct Cache(HashMap<String, u64>);
+    ///
+    /// impl HostCallLayer<String, u64> for Cache {
+    ///     fn call(&mut self, input: String, next: &mut HostCallNext<String, u64>)
+    ///         -> Result<u64, PlugitinError>
+    ///     {
+    ///         if let Some(output) = self.0.get(&input) {
+    ///             return Ok(*output);
+    ///         }
+    ///         let output = next(input.clone())?;
+    ///         self.0.insert(input, output);
+    ///         Ok(output)
+    ///     }
+    /// }
+    ///
+    /// struct WordCounter;
+    ///
+    /// impl Plugin for WordCounter {
+    ///     type ClientCallInput = String;
+    ///     type ClientCallOutput = u64;
+    ///     type HostCallInput = String;
+    ///     type HostCallOutput = u64;
+    ///
+    ///     fn new(host: &mut Host<String, u64>) -> Self {
+    ///         host.add_layer(Cache::default());
+    ///         WordCounter
+    ///     }
+    ///
+    ///     fn call(&mut self, input: &String, host: &mut Host<String, u64>) -> u64 {
+    ///         input.split_whitespace().map(|word| host.call(word.to_string())).sum()
+    ///     }
+    /// }
+    /// ```
+    pub fn add_layer<L: HostCallLayer<In, Out> + 'static>(&mut self, layer: L) {
+        self.state.layers.push(Box::new(layer));
+    }
+
+    /// Returns the read-only dataset the host shared with this plugin instance, or an empty
+    /// slice if it has not shared one. The data lives in the plugin's own memory, so it is
+    /// read in place rather than copied for each call. Hosts share data once the plugin has
+    /// been constructed, so it is always empty in `Plugin::new`.
+    pub fn shared_data(&self) -> &[u8] {
+        let data = self.state.shared_data;
+        if data.len == 0 {
+            return &[];
         }
+        unsafe { std::slice::from_raw_parts(data.ptr as *const u8, data.len as usize) }
+    }
 
-        // Serialize into host's input.
-        let input_slice: &mut [u8] = &mut self.host_call_input_buffer;
-        serialize_into(input_slice, &input)
-            .expect("Failed to serialize host call input");
+    /// Returns the context the host attached to the current client call. Empty outside of
+    /// client calls, and if the host did not set one.
+    pub fn context(&self) -> &CallContext {
+        &self.state.call_context
+    }
 
-        let input_ptr = self.host_call_input_buffer.as_mut_ptr() as u32;
-        let input_packed = pack_buffer_desc(input_ptr, input_len as u32);
+    /// Returns the current time according to the host. Hosts running in deterministic mode
+    /// serve virtual time here, so plugins should prefer this over `SystemTime::now`, which
+    /// is unavailable on wasm32-unknown-unknown anyway.
+    pub fn now(&self) -> SystemTime {
+        let nanos = unsafe { plugitin_host_now(self.info) };
+        UNIX_EPOCH + Duration::from_nanos(nanos)
+    }
 
-        // Invoke the host.
-        let output_packed = unsafe { plugitin_host_call(self.info, input_packed) };
-        let (output_ptr, output_len) = unpack_buffer_desc(output_packed);
+    /// Returns 64 random bits from the host. Hosts running in deterministic mode serve
+    /// these from a seeded generator, so the same inputs always produce the same outputs.
+    pub fn random(&self) -> u64 {
+        unsafe { plugitin_host_random(self.info) }
+    }
 
-        // Deserialize from host's output.
-        let output_slice: &[u8] = unsafe {
-            std::slice::from_raw_parts(output_ptr as *mut u8, output_len as usize)
+    /// Returns the value stored under `key` in this plugin's key-value storage on the host,
+    /// if any. Requires the host to grant the key-value capability.
+    pub fn kv_get(&self, key: &[u8]) -> Option<Vec<u8>> {
+        let key_packed = pack_buffer_desc(key.as_ptr() as u32, key.len() as u32);
+        // Most values are small, so guess a size up front and only retry with an exactly
+        // sized buffer when the guess was too small.
+        let mut value = vec![0u8; KV_INITIAL_VALUE_CAPACITY];
+        loop {
+            let dest_packed = pack_buffer_desc(value.as_mut_ptr() as u32, value.len() as u32);
+            let len = unsafe { plugitin_host_kv_get(self.info, key_packed, dest_packed) };
+            if len == KV_ABSENT {
+                return None;
+            }
+            // The length comes from the host, so it must be checked before it is trusted.
+            check_message_size(len, self.max_incoming_message_size);
+            let len = u32::try_from(len)
+                .or_fail(ERROR_MESSAGE_TOO_LARGE, "Host reported an oversized value") as usize;
+            if len <= value.len() {
+                value.truncate(len);
+                return Some(value);
+            }
+            value = vec![0u8; len];
+        }
+    }
+
+    /// Stores `value` under `key` in this plugin's key-value storage on the host,
+    /// replacing any previous value. Requires the host to grant the key-value capability.
+    pub fn kv_set(&self, key: &[u8], value: &[u8]) {
+        let key_packed = pack_buffer_desc(key.as_ptr() as u32, key.len() as u32);
+        let value_packed = pack_buffer_desc(value.as_ptr() as u32, value.len() as u32);
+        unsafe { plugitin_host_kv_set(self.info, key_packed, value_packed) }
+    }
+
+    /// Removes `key` from this plugin's key-value storage on the host. Returns whether a
+    /// value was present. Requires the host to grant the key-value capability.
+    pub fn kv_delete(&self, key: &[u8]) -> bool {
+        let key_packed = pack_buffer_desc(key.as_ptr() as u32, key.len() as u32);
+        unsafe { plugitin_host_kv_delete(self.info, key_packed) != 0 }
+    }
+
+    /// Makes an HTTP request through the host. Requires the host to grant the HTTP
+    /// capability. The host decides which hosts and methods are allowed and how large
+    /// requests and responses may be; requests it refuses fail with an `HttpError`.
+    pub fn http_request(&mut self, request: HttpRequest) -> Result<HttpResponse, HttpError> {
+        let request: WireRequest = request.into();
+        let request_len = serialized_size(&request)
+            .or_fail(ERROR_SERIALIZE_FAILED, "Failed to compute serialized size for HTTP request");
+        check_message_size(request_len, self.max_message_size);
+        let mut request_buffer = Vec::with_capacity(request_len as usize);
+        serialize_into(&mut request_buffer, &request)
+            .or_fail(ERROR_SERIALIZE_FAILED, "Failed to serialize HTTP request");
+
+        let request_packed = pack_buffer_desc(
+            request_buffer.as_ptr() as u32, request_buffer.len() as u32);
+        let response_packed = unsafe { plugitin_host_http(self.info, request_packed) };
+        let (response_ptr, response_len) = unpack_buffer_desc(response_packed);
+
+        let response_slice: &[u8] = unsafe {
+            std::slice::from_raw_parts(response_ptr as *const u8, response_len as usize)
+        };
+        let response: WireResponse = deserialize_message(response_slice, self.deserialize_limit)
+            .or_fail(ERROR_DESERIALIZE_FAILED, "Failed to deserialize HTTP response");
+        free_host_output(self.dealloc, response_ptr, response_len);
+        response_from_wire(response)
+    }
+
+    /// Asks the host to invoke `Plugin::on_timer` with `token` once `delay` has elapsed.
+    /// Each call schedules an independent timer; reschedule from `on_timer` for periodic
+    /// work.
+    pub fn schedule(&self, delay: Duration, token: u64) {
+        let delay_nanos = u64::try_from(delay.as_nanos()).unwrap_or(u64::MAX);
+        unsafe { plugitin_host_schedule(self.info, delay_nanos, token) }
+    }
+
+    /// Returns a writer whose output is forwarded to the host as this plugin's standard
+    /// output. Plugins should use this instead of `println!`, which writes nowhere on
+    /// wasm32-unknown-unknown.
+    pub fn stdout(&self) -> HostWriter {
+        HostWriter { info: self.info, stream: STDOUT_STREAM }
+    }
+
+    /// Returns a writer whose output is forwarded to the host as this plugin's standard
+    /// error. Plugins should use this instead of `eprintln!`, which writes nowhere on
+    /// wasm32-unknown-unknown.
+    pub fn stderr(&self) -> HostWriter {
+        HostWriter { info: self.info, stream: STDERR_STREAM }
+    }
+}
+
+/// Writer which forwards text to one of the plugin's output streams on the host. Obtained
+/// from `Host::stdout` or `Host::stderr`.
+#[derive(Clone, Copy, Debug)]
+pub struct HostWriter {
+    info: u32,
+    stream: u32,
+}
+
+impl std::io::Write for HostWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let text_packed = pack_buffer_desc(buf.as_ptr() as u32, buf.len() as u32);
+        unsafe { plugitin_host_output(self.info, self.stream, text_packed) };
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+/// A minimal global allocator for plugins which care more about binary size than memory
+/// reuse. Memory is handed out by bumping a pointer through pages requested from the
+/// runtime, and is only reclaimed when the most recent allocation is freed. Suits plugins
+/// whose allocations are few or short-lived.
+///
+/// # Features
+/// Only available if the **tiny** feature is enabled.
+///
+/// # Examples
+///
+/// ```
+/// use plugitin::client::BumpAllocator;
+///
+/// #[global_allocator]
+/// static ALLOCATOR: BumpAllocator = BumpAllocator::new();
+///
+/// let numbers: Vec<u32> = (0..1000).collect();
+/// assert_eq!(numbers.iter().sum::<u32>(), 499500);
+/// ```
+#[cfg(feature = "tiny")]
+pub struct BumpAllocator {
+    // Start and end of the unused part of the current chunk.
+    free: Mutex<(usize, usize)>,
+}
+
+#[cfg(feature = "tiny")]
+impl BumpAllocator {
+    pub const fn new() -> Self {
+        Self { free: Mutex::new((0, 0)) }
+    }
+}
+
+#[cfg(feature = "tiny")]
+impl Default for BumpAllocator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// Granularity with which the bump allocator requests memory, matching the wasm page size.
+#[cfg(feature = "tiny")]
+const BUMP_CHUNK_SIZE: usize = 64 * 1024;
+
+#[cfg(feature = "tiny")]
+unsafe impl std::alloc::GlobalAlloc for BumpAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let mut free = match self.free.lock() {
+            Ok(free) => free,
+            Err(_) => return std::ptr::null_mut(),
+        };
+        let (mut next, mut end) = *free;
+        let mut start = (next + layout.align() - 1) & !(layout.align() - 1);
+        if next == 0 || start + layout.size() > end {
+            let chunk_len = (layout.size() + layout.align() + BUMP_CHUNK_SIZE - 1)
+                & !(BUMP_CHUNK_SIZE - 1);
+            let chunk = match bump_grow(chunk_len) {
+                Some(chunk) => chunk,
+                None => return std::ptr::null_mut(),
+            };
+            // Chunks from wasm memory growth are usually contiguous with the previous one.
+            if chunk != end || next == 0 {
+                next = chunk;
+            }
+            end = chunk + chunk_len;
+            start = (next + layout.align() - 1) & !(layout.align() - 1);
+        }
+        *free = (start + layout.size(), end);
+        start as *mut u8
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        if let Ok(mut free) = self.free.lock() {
+            if ptr as usize + layout.size() == free.0 {
+                free.0 = ptr as usize;
+            }
+        }
+    }
+}
+
+// Requests a fresh, zeroed chunk of memory for the bump allocator.
+#[cfg(all(feature = "tiny", target_arch = "wasm32"))]
+fn bump_grow(len: usize) -> Option<usize> {
+    let pages = len / BUMP_CHUNK_SIZE;
+    let previous_pages = core::arch::wasm32::memory_grow(0, pages);
+    if previous_pages == usize::MAX {
+        None
+    } else {
+        Some(previous_pages * BUMP_CHUNK_SIZE)
+    }
+}
+
+// Outside of wasm, for example when testing plugins natively, chunks come from the system
+// allocator and are never returned.
+#[cfg(all(feature = "tiny", not(target_arch = "wasm32")))]
+fn bump_grow(len: usize) -> Option<usize> {
+    let layout = Layout::from_size_align(len, BUMP_CHUNK_SIZE).ok()?;
+    let chunk = unsafe { std::alloc::GlobalAlloc::alloc_zeroed(&std::alloc::System, layout) };
+    if chunk.is_null() {
+        None
+    } else {
+        Some(chunk as usize)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct First;
+    struct Second;
+
+    fn index(handle: u32) -> usize {
+        (handle & HANDLE_INDEX_MASK) as usize
+    }
+
+    #[test]
+    fn handles_resolve_to_their_instance() {
+        let handle = insert_handle::<First>(0x1000);
+        assert_eq!(lookup_handle::<First>(handle), Ok(0x1000));
+        assert_eq!(remove_handle::<First>(handle), Ok(0x1000));
+    }
+
+    #[test]
+    fn destroyed_handles_are_stale_even_after_reuse() {
+        let handle = insert_handle::<First>(0x2000);
+        remove_handle::<First>(handle).unwrap();
+        assert_eq!(lookup_handle::<First>(handle), Err(HandleError::Stale(handle)));
+        assert_eq!(remove_handle::<First>(handle), Err(HandleError::Stale(handle)));
+
+        // Fill free slots until the destroyed handle's slot is reused, possibly by another
+        // test running concurrently, or retired by one.
+        let mut reused = Vec::new();
+        let is_free = |slot: &HandleSlot| slot.info.is_none() && slot.generation != 0;
+        while is_free(&handle_slots()[index(handle)]) {
+            reused.push(insert_handle::<First>(0x3000));
+        }
+        assert_eq!(lookup_handle::<First>(handle), Err(HandleError::Stale(handle)));
+        for new_handle in reused {
+            remove_handle::<First>(new_handle).unwrap();
+        }
+    }
+
+    #[test]
+    fn handles_are_checked_against_their_plugin() {
+        let handle = insert_handle::<First>(0x4000);
+        assert_eq!(lookup_handle::<Second>(handle), Err(HandleError::WrongPlugin(handle)));
+        assert_eq!(remove_handle::<Second>(handle), Err(HandleError::WrongPlugin(handle)));
+        assert_eq!(remove_handle::<First>(handle), Ok(0x4000));
+    }
+
+    #[test]
+    fn unknown_and_zero_handles_are_rejected() {
+        let handle = HANDLE_INDEX_MASK;
+        assert_eq!(lookup_handle::<First>(handle), Err(HandleError::Unknown(handle)));
+        assert!(lookup_handle::<First>(0).is_err());
+    }
+
+    #[test]
+    fn zero_sized_allocations_skip_the_allocator() {
+        let layout = Layout::from_size_align(0, 8).unwrap();
+        assert_eq!(allocate(layout, |_| panic!("allocator called")), 8);
+    }
+
+    #[test]
+    fn exhausted_slots_are_retired() {
+        let handle = insert_handle::<First>(0x5000);
+        let old_handle = {
+            let mut slots = handle_slots();
+            slots[index(handle)].generation = u16::MAX;
+            make_handle(index(handle), u16::MAX)
         };
-        deserialize_from(output_slice).expect("Failed to deserialize host call output")
+        remove_handle::<First>(old_handle).unwrap();
+        assert_eq!(handle_slots()[index(handle)].generation, 0);
+        assert_eq!(lookup_handle::<First>(old_handle), Err(HandleError::Stale(old_handle)));
+        assert_eq!(lookup_handle::<First>(handle), Err(HandleError::Stale(handle)));
+
+        let new_handle = insert_handle::<First>(0x6000);
+        assert_ne!(index(new_handle), index(handle));
+        remove_handle::<First>(new_handle).unwrap();
+    }
+
+    #[cfg(not(feature = "tiny"))]
+    #[test]
+    fn destroy_is_refused_during_init() {
+        struct Unit;
+
+        impl Plugin for Unit {
+            type ClientCallInput = ();
+            type ClientCallOutput = ();
+            type HostCallInput = ();
+            type HostCallOutput = ();
+
+            fn new(_: &mut Host<(), ()>) -> Self {
+                Unit
+            }
+
+            fn call(&mut self, _: &(), _: &mut Host<(), ()>) {}
+        }
+
+        let handle = std::cell::Cell::new(0);
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            plugitin_init_impl::<Unit, _>(|host| {
+                handle.set(host.info);
+                plugitin_destroy_impl::<Unit>(host.info);
+                Unit
+            })
+        }));
+        assert!(result.is_err());
+
+        // The instance must still be registered, since the constructor was using it.
+        let ptr = remove_handle::<Unit>(handle.get()).unwrap();
+        drop(unsafe { Box::from_raw(ptr as *mut PluginInfo<Unit>) });
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,146 @@
+use std::cell::Cell;
+use std::io;
+use std::marker::PhantomData;
+
+use bincode::{BincodeRead, Options};
+use serde::de::Visitor;
+use serde::Deserialize;
+
+use crate::error::DeserializeDiagnostics;
+
+// Name of the codec used for messages, as reported in diagnostics.
+const CODEC: &str = "bincode";
+
+// Number of bytes either side of the failure offset included in diagnostics.
+const DIAGNOSTIC_WINDOW: usize = 16;
+
+/// Deserializes a message received from the other side of the plugin boundary, which is
+/// not trusted. Bincode may consume at most `limit` bytes, and never more than the message
+/// actually contains, so that length prefixes claiming more data than was received fail
+/// up front instead of triggering huge allocations. The encoding is the same one used by
+/// `bincode::serialize`. Failures are described by diagnostics locating the offending
+/// bytes.
+pub(crate) fn deserialize_message<'a, T>(message: &'a [u8], limit: Option<u64>)
+    -> Result<T, DeserializeDiagnostics>
+    where T: Deserialize<'a>
+{
+    let len = message.len() as u64;
+    let consumed = Cell::new(0);
+    let reader = TrackingReader { remaining: message, consumed: &consumed };
+    bincode::DefaultOptions::new()
+        .with_fixint_encoding()
+        .allow_trailing_bytes()
+        .with_limit(limit.map_or(len, |limit| limit.min(len)))
+        .deserialize_from_custom_seed(PhantomData::<T>, reader)
+        .map_err(|err| diagnose::<T>(message, consumed.get(), &err))
+}
+
+fn diagnose<T>(message: &[u8], offset: usize, err: &bincode::Error) -> DeserializeDiagnostics {
+    let window_start = offset.saturating_sub(DIAGNOSTIC_WINDOW);
+    let window_end = (offset + DIAGNOSTIC_WINDOW).min(message.len());
+    DeserializeDiagnostics {
+        type_name: std::any::type_name::<T>().to_string(),
+        codec: CODEC.to_string(),
+        payload_len: message.len(),
+        offset,
+        window_start,
+        window: message[window_start..window_end].to_vec(),
+        message: err.to_string(),
+    }
+}
+
+// Reads from a borrowed message like bincode's own slice reader, but keeps track of how
+// many bytes have been consumed so that failures can be located.
+struct TrackingReader<'a, 'c> {
+    remaining: &'a [u8],
+    consumed: &'c Cell<usize>,
+}
+
+impl<'a, 'c> TrackingReader<'a, 'c> {
+    fn take(&mut self, length: usize) -> bincode::Result<&'a [u8]> {
+        if length > self.remaining.len() {
+            let eof = io::Error::new(io::ErrorKind::UnexpectedEof, "message ended early");
+            return Err(Box::new(bincode::ErrorKind::Io(eof)));
+        }
+        let (taken, remaining) = self.remaining.split_at(length);
+        self.remaining = remaining;
+        self.consumed.set(self.consumed.get() + length);
+        Ok(taken)
+    }
+}
+
+impl<'a, 'c> io::Read for TrackingReader<'a, 'c> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let length = buf.len().min(self.remaining.len());
+        buf[..length].copy_from_slice(&self.remaining[..length]);
+        self.remaining = &self.remaining[length..];
+        self.consumed.set(self.consumed.get() + length);
+        Ok(length)
+    }
+}
+
+impl<'a, 'c> BincodeRead<'a> for TrackingReader<'a, 'c> {
+    fn forward_read_str<V>(&mut self, length: usize, visitor: V) -> bincode::Result<V::Value>
+        where V: Visitor<'a>
+    {
+        let bytes = self.take(length)?;
+        let string = std::str::from_utf8(bytes)
+            .map_err(|err| Box::new(bincode::ErrorKind::InvalidUtf8Encoding(err)))?;
+        visitor.visit_borrowed_str(string)
+    }
+
+    fn get_byte_buffer(&mut self, length: usize) -> bincode::Result<Vec<u8>> {
+        self.take(length).map(|bytes| bytes.to_vec())
+    }
+
+    fn forward_read_bytes<V>(&mut self, length: usize, visitor: V) -> bincode::Result<V::Value>
+        where V: Visitor<'a>
+    {
+        visitor.visit_borrowed_bytes(self.take(length)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn deserializes_messages() {
+        let message = bincode::serialize(&(7u32, "ferris")).unwrap();
+        let value: (u32, &str) = deserialize_message(&message, None).unwrap();
+        assert_eq!(value, (7, "ferris"));
+    }
+
+    #[test]
+    fn rejects_length_prefixes_longer_than_message() {
+        // A vector claiming 2^40 elements, followed by a single byte.
+        let mut message = (1u64 << 40).to_le_bytes().to_vec();
+        message.push(0);
+        let err = deserialize_message::<Vec<u8>>(&message, None).unwrap_err();
+        assert_eq!(err.payload_len, 9);
+        assert!(err.offset <= 9);
+        assert!(err.message.contains("limit"), "{}", err.message);
+
+        // Strings are read in one piece, so their length is checked before reading.
+        let err = deserialize_message::<String>(&message, None).unwrap_err();
+        assert_eq!(err.offset, 8);
+        assert!(err.message.contains("limit"), "{}", err.message);
+    }
+
+    #[test]
+    fn honours_lower_limit() {
+        let message = bincode::serialize(&vec![1u8; 32]).unwrap();
+        assert!(deserialize_message::<Vec<u8>>(&message, Some(16)).is_err());
+        assert!(deserialize_message::<Vec<u8>>(&message, Some(1000)).is_ok());
+    }
+
+    #[test]
+    fn diagnoses_truncated_messages() {
+        let message = bincode::serialize(&(1u64, 2u64)).unwrap();
+        let err = deserialize_message::<(u64, u64)>(&message[..12], None).unwrap_err();
+        assert_eq!(err.codec, "bincode");
+        assert_eq!(err.offset, 8);
+        assert_eq!(err.window_start, 0);
+        assert_eq!(err.window, message[..12].to_vec());
+    }
+}
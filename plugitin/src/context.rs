@@ -0,0 +1,72 @@
+//! Per-call context, which hosts attach to calls into a plugin so that cross-cutting
+//! request metadata does not need to be part of every interface's input type.
+//!
+//! # Features
+//! This module is only available if the **client** or **host** feature is enabled.
+
+use std::collections::BTreeMap;
+
+/// String key-value metadata about a client call, such as a correlation ID. Set by the host
+/// and read by the plugin through `Host::context`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct CallContext {
+    entries: BTreeMap<String, String>,
+}
+
+impl CallContext {
+    /// Key under which the correlation ID of the call is stored.
+    pub const CORRELATION_ID: &'static str = "correlation_id";
+    /// Key under which the tenant the call is made on behalf of is stored.
+    pub const TENANT: &'static str = "tenant";
+    /// Key under which the locale of the call, such as `en-US`, is stored.
+    pub const LOCALE: &'static str = "locale";
+
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns the value stored under `key`.
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.entries.get(key).map(String::as_str)
+    }
+
+    /// Stores `value` under `key`, returning the value previously stored there.
+    pub fn insert<K: Into<String>, V: Into<String>>(&mut self, key: K, value: V)
+        -> Option<String>
+    {
+        self.entries.insert(key.into(), value.into())
+    }
+
+    pub fn correlation_id(&self) -> Option<&str> {
+        self.get(Self::CORRELATION_ID)
+    }
+
+    pub fn tenant(&self) -> Option<&str> {
+        self.get(Self::TENANT)
+    }
+
+    pub fn locale(&self) -> Option<&str> {
+        self.get(Self::LOCALE)
+    }
+
+    /// Iterates over the entries in key order.
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.entries.iter().map(|(key, value)| (key.as_str(), value.as_str()))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    #[cfg(feature = "host")]
+    pub(crate) fn to_wire(&self) -> &WireContext {
+        &self.entries
+    }
+
+    #[cfg(feature = "client")]
+    pub(crate) fn from_wire(entries: WireContext) -> Self {
+        Self { entries }
+    }
+}
+
+pub(crate) type WireContext = BTreeMap<String, String>;
@@ -0,0 +1,169 @@
+use std::time::Duration;
+
+use crate::abi::BufferDesc;
+
+/// Errors produced by plugitin when a call across the plugin boundary cannot be completed.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum PlugitinError {
+    /// The other side of the boundary produced a buffer descriptor which does not lie
+    /// entirely within the plugin's linear memory.
+    InvalidBufferDescriptor {
+        desc: BufferDesc,
+        memory_len: usize,
+    },
+    /// A serialized message exceeded the configured maximum message size. The check is
+    /// performed before any memory is allocated for the message.
+    MessageTooLarge {
+        size: u64,
+        limit: u64,
+    },
+    /// A message which should have been wrapped in a versioned envelope was too short to
+    /// contain the envelope header.
+    InvalidEnvelope,
+    /// A versioned message was produced for an interface version that the receiver does
+    /// not know how to migrate.
+    UnsupportedVersion {
+        found: u32,
+        expected: u32,
+    },
+    /// A plugin wrote to an output stream identifier which is not defined by the ABI.
+    UnknownOutputStream(u32),
+    /// A host-side storage backend failed.
+    Storage(String),
+    /// A message could not be deserialized into the expected type.
+    DeserializeFailed(DeserializeDiagnostics),
+    /// A message could not be serialized.
+    SerializeFailed(String),
+    /// A plugin's manifest section could not be parsed.
+    InvalidManifest(String),
+    /// A plugin exceeded its rate limits. `retry_after` says how long until calls are
+    /// accepted again, or is `None` if waiting will not help: the plugin used up the host
+    /// calls or bytes allowed for the current call, or is allowed no calls at all.
+    RateLimited {
+        retry_after: Option<Duration>,
+    },
+    /// The host answered a host call with `abi::HOST_CALL_REFUSED`. The plugin is not told
+    /// why: the host may have been rate limiting it, its callback queue may have been full,
+    /// or the call may have exceeded a budget for the current client call.
+    HostCallRefused,
+    /// A plugin failed too many times in a row and is quarantined for `retry_after`.
+    Quarantined {
+        retry_after: Duration,
+    },
+    /// A recorded session could not be replayed, because it was recorded without payloads
+    /// or because the plugin diverged from it.
+    Replay(String),
+    /// A host callback queue was full and its policy is `OverflowPolicy::Error`.
+    QueueFull {
+        capacity: usize,
+    },
+    /// The host passed memory to `plugitin_dealloc` which it had not allocated with
+    /// `plugitin_alloc` or `plugitin_alloc_uninit`, or with a different size or alignment.
+    InvalidDealloc {
+        ptr: u32,
+        size: u32,
+        align: u32,
+    },
+    /// A plugin allocated memory for the host which overlaps memory it had already
+    /// allocated for the host and which is still live.
+    OverlappingAllocation {
+        ptr: u32,
+        size: u32,
+    },
+    /// Memory allocated in a plugin on behalf of the host was never freed.
+    Leaked {
+        allocations: usize,
+        bytes: u64,
+    },
+}
+
+impl std::fmt::Display for PlugitinError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            PlugitinError::InvalidBufferDescriptor { desc, memory_len } => write!(
+                f,
+                "buffer descriptor (ptr {:#x}, len {}) lies outside of linear memory of {} bytes",
+                desc.ptr, desc.len, memory_len),
+            PlugitinError::MessageTooLarge { size, limit } => write!(
+                f, "message of {} bytes exceeds the limit of {} bytes", size, limit),
+            PlugitinError::InvalidEnvelope => write!(
+                f, "message is too short to contain a version envelope"),
+            PlugitinError::UnsupportedVersion { found, expected } => write!(
+                f, "message has interface version {} but version {} was expected",
+                found, expected),
+            PlugitinError::UnknownOutputStream(stream) => write!(
+                f, "unknown output stream {}", stream),
+            PlugitinError::Storage(message) => write!(f, "storage error: {}", message),
+            PlugitinError::DeserializeFailed(diagnostics) => diagnostics.fmt(f),
+            PlugitinError::SerializeFailed(message) => write!(
+                f, "failed to serialize message: {}", message),
+            PlugitinError::InvalidManifest(message) => write!(
+                f, "invalid plugin manifest: {}", message),
+            PlugitinError::RateLimited { retry_after: Some(retry_after) } => write!(
+                f, "plugin exceeded its call rate limit; retry after {:?}", retry_after),
+            PlugitinError::RateLimited { retry_after: None } => write!(
+                f, "plugin exceeded a rate limit which does not recover over time"),
+            PlugitinError::HostCallRefused => write!(f, "host refused the host call"),
+            PlugitinError::Quarantined { retry_after } => write!(
+                f, "plugin is quarantined after repeated failures; retry after {:?}",
+                retry_after),
+            PlugitinError::Replay(message) => write!(f, "replay failed: {}", message),
+            PlugitinError::QueueFull { capacity } => write!(
+                f, "host callback queue is full with {} items", capacity),
+            PlugitinError::InvalidDealloc { ptr, size, align } => write!(
+                f, "freed memory (ptr {:#x}, size {}, align {}) which was not allocated",
+                ptr, size, align),
+            PlugitinError::OverlappingAllocation { ptr, size } => write!(
+                f, "allocation (ptr {:#x}, size {}) overlaps live memory", ptr, size),
+            PlugitinError::Leaked { allocations, bytes } => write!(
+                f, "{} allocations totalling {} bytes were never freed", allocations, bytes),
+        }
+    }
+}
+
+impl PlugitinError {
+    /// Returns whether the failed operation may succeed if it is retried later.
+    pub fn is_transient(&self) -> bool {
+        matches!(
+            self,
+            PlugitinError::RateLimited { retry_after: Some(_) }
+                | PlugitinError::Quarantined { .. })
+    }
+}
+
+impl std::error::Error for PlugitinError {}
+
+/// Describes why a message received across the plugin boundary could not be deserialized,
+/// including the bytes around the point of failure.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DeserializeDiagnostics {
+    /// Name of the Rust type the message was deserialized into.
+    pub type_name: String,
+    /// Name of the codec the message was encoded with.
+    pub codec: String,
+    /// Length of the message in bytes.
+    pub payload_len: usize,
+    /// Number of bytes consumed before deserialization failed.
+    pub offset: usize,
+    /// Offset of the first byte of `window` within the message.
+    pub window_start: usize,
+    /// The bytes of the message surrounding `offset`.
+    pub window: Vec<u8>,
+    /// The codec's description of the failure.
+    pub message: String,
+}
+
+impl std::fmt::Display for DeserializeDiagnostics {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f, "failed to deserialize `{}` from {}-byte {} message at offset {}: {}",
+            self.type_name, self.payload_len, self.codec, self.offset, self.message)?;
+        write!(f, "\n  {:08x}:", self.window_start)?;
+        for (index, byte) in self.window.iter().enumerate() {
+            // Mark the byte deserialization stopped at.
+            let marker = if self.window_start + index == self.offset { '>' } else { ' ' };
+            write!(f, "{}{:02x}", marker, byte)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,2099 @@
+//! Code used by plugin hosts.
+//!
+//! # Features
+//! This module is only available if the **host** feature is enabled.
+
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, BinaryHeap, HashMap, VecDeque};
+use std::hash::{BuildHasher, Hasher};
+use std::io::{self, Write};
+use std::ops::Range;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Condvar, Mutex, MutexGuard};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::abi::{
+    split_envelope, unpack_buffer_sizes, write_envelope_header, BufferDesc,
+    ENVELOPE_HEADER_LEN, KV_ABSENT, STDERR_STREAM, STDOUT_STREAM,
+};
+use crate::codec::deserialize_message;
+use crate::context::CallContext;
+use crate::http::{
+    response_to_wire, HttpError, HttpRequest, HttpResponse, HttpTarget, WireRequest,
+};
+use crate::PlugitinError;
+
+use bincode::{serialize_into, serialized_size};
+use serde::{Deserialize, Serialize};
+
+/// Handles calls made by a plugin to the host. This is the host-side counterpart of the
+/// client's `Plugin` trait: `Input` corresponds to the plugin's `HostCallInput` and
+/// `Output` to its `HostCallOutput`.
+pub trait HostHandler {
+    type Input  : for<'de> Deserialize<'de>;
+    type Output : Serialize;
+
+    /// Invoked when the plugin calls the host.
+    fn handle(&mut self, input: Self::Input) -> Self::Output;
+}
+
+/// Runs a host call through a handler at the byte level. `input` is the serialized message
+/// the plugin passed to `plugitin_host_call`, which should have been obtained through
+/// `MessageLimits::read_incoming`. Returns the serialized output, which the host must then
+/// copy into memory allocated by the plugin. The output is checked against `max_outgoing`
+/// before it is serialized.
+pub fn handle_host_call<H: HostHandler>(
+    handler: &mut H,
+    input: &[u8],
+    limits: &MessageLimits)
+    -> Result<Vec<u8>, PlugitinError>
+{
+    let input: H::Input = deserialize_message(input, None)
+        .map_err(PlugitinError::DeserializeFailed)?;
+
+    let output = handler.handle(input);
+
+    let output_len = serialized_size(&output)
+        .map_err(|err| PlugitinError::SerializeFailed(err.to_string()))?;
+    limits.check_outgoing(output_len)?;
+
+    let mut buffer = Vec::with_capacity(output_len as usize);
+    serialize_into(&mut buffer, &output)
+        .map_err(|err| PlugitinError::SerializeFailed(err.to_string()))?;
+    Ok(buffer)
+}
+
+/// Resolves a packed buffer descriptor produced by a plugin to the byte range it
+/// describes within the plugin's linear memory, which is `memory_len` bytes long. The
+/// plugin is not trusted, so descriptors which overflow or extend past the end of memory
+/// are rejected with `PlugitinError::InvalidBufferDescriptor`.
+pub fn buffer_range(memory_len: usize, packed: u64) -> Result<Range<usize>, PlugitinError> {
+    let desc = BufferDesc::unpack(packed);
+    let start = desc.ptr as usize;
+    match start.checked_add(desc.len as usize) {
+        Some(end) if end <= memory_len => Ok(start..end),
+        _ => Err(PlugitinError::InvalidBufferDescriptor { desc, memory_len }),
+    }
+}
+
+/// Borrows the buffer described by a packed descriptor produced by a plugin, such as the
+/// result of `plugitin_client_call` or the input of `plugitin_host_call`. See
+/// `buffer_range` for the validation performed.
+pub fn read_buffer(memory: &[u8], packed: u64) -> Result<&[u8], PlugitinError> {
+    let range = buffer_range(memory.len(), packed)?;
+    Ok(&memory[range])
+}
+
+/// Mutably borrows the buffer described by a packed descriptor, for example memory the
+/// plugin returned from `plugitin_alloc` which the host is about to write into. See
+/// `buffer_range` for the validation performed.
+pub fn write_buffer(memory: &mut [u8], packed: u64) -> Result<&mut [u8], PlugitinError> {
+    let range = buffer_range(memory.len(), packed)?;
+    Ok(&mut memory[range])
+}
+
+/// Serializes a client call input wrapped in a versioned envelope for `version`, for
+/// plugins which set `Plugin::INTERFACE_VERSION`. The message is checked against
+/// `max_outgoing` before it is serialized.
+pub fn write_versioned_input<T: Serialize>(
+    input: &T,
+    version: u32,
+    limits: &MessageLimits)
+    -> Result<Vec<u8>, PlugitinError>
+{
+    let payload_len = serialized_size(input)
+        .map_err(|err| PlugitinError::SerializeFailed(err.to_string()))?;
+    let message_len = payload_len + ENVELOPE_HEADER_LEN as u64;
+    limits.check_outgoing(message_len)?;
+
+    let mut buffer = vec![0u8; ENVELOPE_HEADER_LEN];
+    buffer.reserve(payload_len as usize);
+    write_envelope_header(&mut buffer, version);
+    serialize_into(&mut buffer, input)
+        .map_err(|err| PlugitinError::SerializeFailed(err.to_string()))?;
+    Ok(buffer)
+}
+
+/// Deserializes a versioned client call output. Outputs produced for `version` are
+/// deserialized directly, while outputs from any other interface version are handed to
+/// `migrate_output` along with their version and raw payload, allowing a newer host to
+/// keep talking to older plugins.
+pub fn read_versioned_output<T, F>(message: &[u8], version: u32, migrate_output: F)
+    -> Result<T, PlugitinError>
+    where T: for<'de> Deserialize<'de>, F: FnOnce(u32, &[u8]) -> Result<T, PlugitinError>
+{
+    let (output_version, payload) = split_envelope(message)
+        .ok_or(PlugitinError::InvalidEnvelope)?;
+    if output_version == version {
+        deserialize_message(payload, None)
+            .map_err(PlugitinError::DeserializeFailed)
+    } else {
+        migrate_output(output_version, payload)
+    }
+}
+
+/// Serializes the context of the next client call, which the host then copies into memory
+/// allocated by the plugin and passes to `plugitin_set_call_context`. The message is
+/// checked against `max_outgoing` before it is serialized.
+pub fn write_call_context(context: &CallContext, limits: &MessageLimits)
+    -> Result<Vec<u8>, PlugitinError>
+{
+    let context = context.to_wire();
+    let context_len = serialized_size(context)
+        .map_err(|err| PlugitinError::SerializeFailed(err.to_string()))?;
+    limits.check_outgoing(context_len)?;
+    let mut buffer = Vec::with_capacity(context_len as usize);
+    serialize_into(&mut buffer, context)
+        .map_err(|err| PlugitinError::SerializeFailed(err.to_string()))?;
+    Ok(buffer)
+}
+
+/// Caps on the size of messages crossing the plugin boundary, so that a hostile plugin
+/// cannot make the host allocate unbounded amounts of memory and vice versa. Incoming
+/// messages are those written by the plugin (client call outputs and host call inputs);
+/// outgoing messages are those written by the host.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct MessageLimits {
+    pub max_incoming: u64,
+    pub max_outgoing: u64,
+}
+
+impl MessageLimits {
+    /// Limits that accept any message addressable by the 32-bit ABI.
+    pub fn unlimited() -> Self {
+        Self {
+            max_incoming: u32::MAX as u64,
+            max_outgoing: u32::MAX as u64,
+        }
+    }
+
+    /// Checks the length of a message received from the plugin. Must be called before the
+    /// host copies or deserializes the message.
+    pub fn check_incoming(&self, size: u64) -> Result<(), PlugitinError> {
+        check_limit(size, self.max_incoming)
+    }
+
+    /// Checks the length of a message the host is about to send to the plugin. Must be
+    /// called before asking the plugin to allocate memory for it.
+    pub fn check_outgoing(&self, size: u64) -> Result<(), PlugitinError> {
+        check_limit(size, self.max_outgoing)
+    }
+
+    /// Borrows an incoming message described by a packed descriptor, validating it as
+    /// `read_buffer` does and additionally enforcing `max_incoming`.
+    pub fn read_incoming<'m>(&self, memory: &'m [u8], packed: u64)
+        -> Result<&'m [u8], PlugitinError>
+    {
+        self.check_incoming(BufferDesc::unpack(packed).len as u64)?;
+        read_buffer(memory, packed)
+    }
+}
+
+impl Default for MessageLimits {
+    fn default() -> Self {
+        Self::unlimited()
+    }
+}
+
+fn check_limit(size: u64, limit: u64) -> Result<(), PlugitinError> {
+    if size > limit {
+        Err(PlugitinError::MessageTooLarge { size, limit })
+    } else {
+        Ok(())
+    }
+}
+
+/// One of a plugin's output streams.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum OutputStream {
+    Stdout,
+    Stderr,
+}
+
+impl OutputStream {
+    /// Converts an ABI stream identifier into an `OutputStream`.
+    pub fn from_abi(stream: u32) -> Option<Self> {
+        match stream {
+            STDOUT_STREAM => Some(OutputStream::Stdout),
+            STDERR_STREAM => Some(OutputStream::Stderr),
+            _ => None,
+        }
+    }
+}
+
+/// Receives text that plugins write to their stdout and stderr writers.
+pub trait OutputHandler {
+    /// Invoked with each chunk of text written by the plugin named `plugin`.
+    fn output(&mut self, plugin: &str, stream: OutputStream, text: &str);
+}
+
+/// Output handler which forwards plugin output to the host process's own stdout and stderr,
+/// prefixing each chunk with the plugin's name.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct StdioOutput;
+
+impl OutputHandler for StdioOutput {
+    fn output(&mut self, plugin: &str, stream: OutputStream, text: &str) {
+        match stream {
+            OutputStream::Stdout => print!("[{}] {}", plugin, text),
+            OutputStream::Stderr => eprint!("[{}] {}", plugin, text),
+        }
+    }
+}
+
+/// Services a call to the `plugitin_host_output` import made by the plugin named
+/// `plugin`. The text is validated like any other incoming message and decoded lossily,
+/// since plugins may split multi-byte characters across writes.
+pub fn handle_output<H: OutputHandler + ?Sized>(
+    handler: &mut H,
+    plugin: &str,
+    memory: &[u8],
+    stream: u32,
+    text_packed: u64,
+    limits: &MessageLimits)
+    -> Result<(), PlugitinError>
+{
+    let stream = OutputStream::from_abi(stream)
+        .ok_or(PlugitinError::UnknownOutputStream(stream))?;
+    let text = limits.read_incoming(memory, text_packed)?;
+    handler.output(plugin, stream, &String::from_utf8_lossy(text));
+    Ok(())
+}
+
+/// Source of the time and randomness served to plugins through `Host::now` and
+/// `Host::random`.
+pub trait Environment {
+    /// Returns the current time in nanoseconds since the Unix epoch.
+    fn now(&mut self) -> u64;
+
+    /// Returns 64 random bits.
+    fn random(&mut self) -> u64;
+}
+
+/// Environment backed by the real system clock and randomness seeded by the operating
+/// system.
+#[derive(Debug)]
+pub struct SystemEnvironment {
+    rng: SplitMix64,
+}
+
+impl SystemEnvironment {
+    pub fn new() -> Self {
+        // RandomState is seeded by the operating system, which is enough for plugins that
+        // only need unpredictable values rather than cryptographic ones.
+        let seed = std::collections::hash_map::RandomState::new().build_hasher().finish();
+        Self { rng: SplitMix64(seed) }
+    }
+}
+
+impl Default for SystemEnvironment {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Environment for SystemEnvironment {
+    fn now(&mut self) -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |since_epoch| since_epoch.as_nanos() as u64)
+    }
+
+    fn random(&mut self) -> u64 {
+        self.rng.next()
+    }
+}
+
+/// Environment for replayable execution. Time is virtual: it starts at a fixed instant and
+/// advances by a fixed step each time it is read. Randomness comes from a generator with a
+/// fixed seed. A plugin given the same inputs in a fresh `DeterministicEnvironment`
+/// therefore always observes the same times and random values.
+#[derive(Clone, Debug)]
+pub struct DeterministicEnvironment {
+    time: u64,
+    step: u64,
+    rng: SplitMix64,
+}
+
+impl DeterministicEnvironment {
+    /// Creates an environment whose clock starts at `start` nanoseconds since the Unix epoch
+    /// and advances by `step` nanoseconds per read, and whose randomness is seeded by `seed`.
+    pub fn new(start: u64, step: u64, seed: u64) -> Self {
+        Self { time: start, step, rng: SplitMix64(seed) }
+    }
+
+    /// Moves the virtual clock forward without it being read.
+    pub fn advance(&mut self, nanos: u64) {
+        self.time = self.time.wrapping_add(nanos);
+    }
+}
+
+impl Environment for DeterministicEnvironment {
+    fn now(&mut self) -> u64 {
+        let now = self.time;
+        self.time = self.time.wrapping_add(self.step);
+        now
+    }
+
+    fn random(&mut self) -> u64 {
+        self.rng.next()
+    }
+}
+
+// Small, fast generator with a well-distributed output for any seed, including zero. See
+// http://prng.di.unimi.it/splitmix64.c.
+#[derive(Clone, Debug)]
+struct SplitMix64(u64);
+
+impl SplitMix64 {
+    fn next(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+}
+
+/// Storage behind the key-value capability. Every plugin gets its own namespace, so
+/// plugins cannot see or overwrite each other's keys.
+pub trait KvBackend {
+    /// Returns the value stored under `key` in `namespace`, if any.
+    fn get(&mut self, namespace: &str, key: &[u8]) -> io::Result<Option<Vec<u8>>>;
+
+    /// Stores `value` under `key` in `namespace`, replacing any previous value.
+    fn set(&mut self, namespace: &str, key: &[u8], value: &[u8]) -> io::Result<()>;
+
+    /// Removes `key` from `namespace`. Returns whether a value was present.
+    fn delete(&mut self, namespace: &str, key: &[u8]) -> io::Result<bool>;
+}
+
+/// Key-value backend which keeps everything in memory. Contents are lost when it is
+/// dropped.
+#[derive(Clone, Debug, Default)]
+pub struct MemoryKvBackend {
+    entries: HashMap<(String, Vec<u8>), Vec<u8>>,
+}
+
+impl MemoryKvBackend {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl KvBackend for MemoryKvBackend {
+    fn get(&mut self, namespace: &str, key: &[u8]) -> io::Result<Option<Vec<u8>>> {
+        Ok(self.entries.get(&(namespace.to_string(), key.to_vec())).cloned())
+    }
+
+    fn set(&mut self, namespace: &str, key: &[u8], value: &[u8]) -> io::Result<()> {
+        self.entries.insert((namespace.to_string(), key.to_vec()), value.to_vec());
+        Ok(())
+    }
+
+    fn delete(&mut self, namespace: &str, key: &[u8]) -> io::Result<bool> {
+        Ok(self.entries.remove(&(namespace.to_string(), key.to_vec())).is_some())
+    }
+}
+
+/// Key-value backend which stores each value in its own file under a root directory, with
+/// one subdirectory per namespace. Namespaces and keys are hex-encoded to form the path,
+/// so arbitrary bytes, including empty keys, are safe to use and cannot escape the root
+/// directory. Long namespaces and keys are split across nested directories to stay within
+/// file name length limits. Values are replaced atomically, so a crash leaves either the
+/// old or the new value in place.
+#[derive(Clone, Debug)]
+pub struct FileKvBackend {
+    root: PathBuf,
+}
+
+// Bytes of a namespace or key encoded into a single path component, which keeps components
+// well below the usual limit of 255 bytes.
+const KV_PATH_SEGMENT_LEN: usize = 64;
+
+// Distinguishes temporary files written by concurrent `set` calls within the process.
+static KV_TEMP_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+impl FileKvBackend {
+    pub fn new<P: Into<PathBuf>>(root: P) -> Self {
+        Self { root: root.into() }
+    }
+
+    fn path(&self, namespace: &str, key: &[u8]) -> PathBuf {
+        let mut path = self.root.clone();
+        push_kv_segments(&mut path, namespace.as_bytes(), 'n');
+        push_kv_segments(&mut path, key, 'v');
+        path
+    }
+}
+
+// Appends the hex encoding of bytes to path, split into components of at most
+// KV_PATH_SEGMENT_LEN bytes each. Intermediate components are prefixed with 'd' and the
+// last with leaf_prefix, so that no component is both a file and a directory.
+fn push_kv_segments(path: &mut PathBuf, bytes: &[u8], leaf_prefix: char) {
+    let mut chunks = bytes.chunks(KV_PATH_SEGMENT_LEN).peekable();
+    if chunks.peek().is_none() {
+        path.push(leaf_prefix.to_string());
+    }
+    while let Some(chunk) = chunks.next() {
+        let prefix = if chunks.peek().is_some() { 'd' } else { leaf_prefix };
+        path.push(format!("{}{}", prefix, hex(chunk)));
+    }
+}
+
+impl KvBackend for FileKvBackend {
+    fn get(&mut self, namespace: &str, key: &[u8]) -> io::Result<Option<Vec<u8>>> {
+        match std::fs::read(self.path(namespace, key)) {
+            Ok(value) => Ok(Some(value)),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+
+    fn set(&mut self, namespace: &str, key: &[u8], value: &[u8]) -> io::Result<()> {
+        let path = self.path(namespace, key);
+        let parent = path.parent().expect("value paths are below the root directory");
+        std::fs::create_dir_all(parent)?;
+        // Write the value next to its final location, then move it into place.
+        let temp = parent.join(format!(
+            "t{}-{}",
+            std::process::id(),
+            KV_TEMP_COUNTER.fetch_add(1, Ordering::Relaxed)));
+        let result = std::fs::File::create(&temp)
+            .and_then(|mut file| {
+                file.write_all(value)?;
+                file.sync_all()
+            })
+            .and_then(|()| std::fs::rename(&temp, &path));
+        if result.is_err() {
+            let _ = std::fs::remove_file(&temp);
+        }
+        result
+    }
+
+    fn delete(&mut self, namespace: &str, key: &[u8]) -> io::Result<bool> {
+        match std::fs::remove_file(self.path(namespace, key)) {
+            Ok(()) => Ok(true),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(false),
+            Err(err) => Err(err),
+        }
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// Services a call to the `plugitin_host_kv_get` import made by the plugin whose namespace
+/// is `namespace`. Returns the value to hand back to the plugin.
+pub fn handle_kv_get<B: KvBackend + ?Sized>(
+    backend: &mut B,
+    namespace: &str,
+    memory: &mut [u8],
+    key_packed: u64,
+    dest_packed: u64,
+    limits: &MessageLimits)
+    -> Result<u64, PlugitinError>
+{
+    let key = limits.read_incoming(memory, key_packed)?.to_vec();
+    let value = match backend.get(namespace, &key).map_err(storage_error)? {
+        Some(value) => value,
+        None => return Ok(KV_ABSENT),
+    };
+    limits.check_outgoing(value.len() as u64)?;
+    let dest = write_buffer(memory, dest_packed)?;
+    if value.len() <= dest.len() {
+        dest[..value.len()].copy_from_slice(&value);
+    }
+    Ok(value.len() as u64)
+}
+
+/// Services a call to the `plugitin_host_kv_set` import made by the plugin whose namespace
+/// is `namespace`.
+pub fn handle_kv_set<B: KvBackend + ?Sized>(
+    backend: &mut B,
+    namespace: &str,
+    memory: &[u8],
+    key_packed: u64,
+    value_packed: u64,
+    limits: &MessageLimits)
+    -> Result<(), PlugitinError>
+{
+    let key = limits.read_incoming(memory, key_packed)?;
+    let value = limits.read_incoming(memory, value_packed)?;
+    backend.set(namespace, key, value).map_err(storage_error)
+}
+
+/// Services a call to the `plugitin_host_kv_delete` import made by the plugin whose
+/// namespace is `namespace`. Returns the value to hand back to the plugin.
+pub fn handle_kv_delete<B: KvBackend + ?Sized>(
+    backend: &mut B,
+    namespace: &str,
+    memory: &[u8],
+    key_packed: u64,
+    limits: &MessageLimits)
+    -> Result<u32, PlugitinError>
+{
+    let key = limits.read_incoming(memory, key_packed)?;
+    let deleted = backend.delete(namespace, key).map_err(storage_error)?;
+    Ok(deleted as u32)
+}
+
+fn storage_error(err: io::Error) -> PlugitinError {
+    PlugitinError::Storage(err.to_string())
+}
+
+/// Restrictions on the HTTP requests plugins may make through the HTTP capability. The
+/// default policy denies everything.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct HttpPolicy {
+    /// Hosts plugins may contact. An entry of the form `*.example.com` allows any
+    /// subdomain of `example.com`. Matching is case-insensitive.
+    pub allowed_hosts: Vec<String>,
+    /// Methods plugins may use, such as `GET`. Matching is case-insensitive.
+    pub allowed_methods: Vec<String>,
+    /// Largest request body, in bytes, plugins may send.
+    pub max_request_body: usize,
+    /// Largest response body, in bytes, that will be handed back to plugins. It is passed to
+    /// `HttpBackend::send` so that larger responses are abandoned while being read.
+    pub max_response_body: usize,
+}
+
+impl HttpPolicy {
+    /// Checks whether `request` may be sent, returning the target it must be sent to.
+    /// Requests setting `Host`, framing or hop-by-hop headers are refused, since the
+    /// backend is responsible for those.
+    pub fn check_request(&self, request: &HttpRequest) -> Result<HttpTarget, HttpError> {
+        let method_allowed = self.allowed_methods.iter()
+            .any(|method| method.eq_ignore_ascii_case(&request.method));
+        if !method_allowed {
+            return Err(HttpError(format!("method {} is not allowed", request.method)));
+        }
+
+        let target = request.target().ok_or_else(|| HttpError(format!(
+            "{} is not an absolute http or https URL", request.url)))?;
+        if !self.allowed_hosts.iter().any(|allowed| host_matches(allowed, &target.host)) {
+            return Err(HttpError(format!("host {} is not allowed", target.host)));
+        }
+
+        for (name, value) in request.headers.iter() {
+            let control = value.bytes().any(|byte| byte.is_ascii_control() && byte != b'\t');
+            if !is_token(name) || control {
+                return Err(HttpError(format!("header {:?} is malformed", name)));
+            }
+            if FORBIDDEN_HEADERS.iter().any(|forbidden| forbidden.eq_ignore_ascii_case(name)) {
+                return Err(HttpError(format!("header {} may not be set by plugins", name)));
+            }
+        }
+
+        if request.body.len() > self.max_request_body {
+            return Err(HttpError(format!(
+                "request body of {} bytes exceeds the limit of {} bytes",
+                request.body.len(), self.max_request_body)));
+        }
+        Ok(target)
+    }
+
+    /// Checks whether `response` may be handed back to the plugin.
+    pub fn check_response(&self, response: &HttpResponse) -> Result<(), HttpError> {
+        if response.body.len() > self.max_response_body {
+            return Err(HttpError(format!(
+                "response body of {} bytes exceeds the limit of {} bytes",
+                response.body.len(), self.max_response_body)));
+        }
+        Ok(())
+    }
+}
+
+// Headers which plugins may not set. Host would redirect an allowed connection to another
+// virtual host, the framing headers could smuggle a second request past the policy, and
+// hop-by-hop headers concern the connection, which belongs to the backend.
+const FORBIDDEN_HEADERS: [&str; 11] = [
+    "Host",
+    "Content-Length",
+    "Transfer-Encoding",
+    "Connection",
+    "Keep-Alive",
+    "Proxy-Connection",
+    "Proxy-Authenticate",
+    "Proxy-Authorization",
+    "TE",
+    "Trailer",
+    "Upgrade",
+];
+
+// Whether name is a valid header name, as defined by RFC 9110.
+fn is_token(name: &str) -> bool {
+    !name.is_empty()
+        && name.bytes()
+            .all(|byte| byte.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&byte))
+}
+
+fn host_matches(allowed: &str, host: &str) -> bool {
+    match allowed.strip_prefix("*.") {
+        Some(domain) => {
+            host.len() > domain.len() + 1
+                && host[host.len() - domain.len()..].eq_ignore_ascii_case(domain)
+                && host.as_bytes()[host.len() - domain.len() - 1] == b'.'
+        }
+        None => allowed.eq_ignore_ascii_case(host),
+    }
+}
+
+/// Performs the HTTP requests that plugins make through the HTTP capability. plugitin does
+/// not include an HTTP client; embedders implement this with the client of their choice.
+pub trait HttpBackend {
+    /// Sends `request` to `target`, which is the target `HttpPolicy` checked. Backends must
+    /// connect to `target` rather than parse `request.url` again, since their own parser
+    /// might disagree with the policy's about where the URL points.
+    ///
+    /// Backends must stop reading the response and return an error as soon as its body
+    /// exceeds `max_response_body` bytes, so that a hostile server cannot make the host
+    /// buffer an arbitrarily large response on the plugin's behalf.
+    fn send(&mut self, target: &HttpTarget, request: &HttpRequest, max_response_body: usize)
+        -> Result<HttpResponse, HttpError>;
+}
+
+/// Services a call to the `plugitin_host_http` import. `request` is the serialized request
+/// the plugin passed, which should have been obtained through
+/// `MessageLimits::read_incoming`. Requests that `policy` refuses are not sent; instead the
+/// plugin receives an `HttpError`. Returns the serialized response, which the host must
+/// then copy into memory allocated by the plugin.
+pub fn handle_http_request<B: HttpBackend + ?Sized>(
+    backend: &mut B,
+    policy: &HttpPolicy,
+    request: &[u8],
+    limits: &MessageLimits)
+    -> Result<Vec<u8>, PlugitinError>
+{
+    let request: WireRequest = deserialize_message(request, None)
+        .map_err(PlugitinError::DeserializeFailed)?;
+    let request = HttpRequest::from(request);
+
+    let response = policy.check_request(&request)
+        .and_then(|target| backend.send(&target, &request, policy.max_response_body))
+        .and_then(|response| policy.check_response(&response).map(|()| response));
+    let response = response_to_wire(response);
+
+    let response_len = serialized_size(&response)
+        .map_err(|err| PlugitinError::SerializeFailed(err.to_string()))?;
+    limits.check_outgoing(response_len)?;
+    let mut buffer = Vec::with_capacity(response_len as usize);
+    serialize_into(&mut buffer, &response)
+        .map_err(|err| PlugitinError::SerializeFailed(err.to_string()))?;
+    Ok(buffer)
+}
+
+/// Memory usage of a plugin instance, so that operators can find the plugins responsible
+/// for memory bloat. The host samples the size of the plugin's linear memory with
+/// `observe_memory_size`, for example after each call, and records the result of the
+/// `plugitin_buffer_sizes` export, if present, with `set_buffer_sizes`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct MemoryStats {
+    /// Size of the plugin's linear memory in bytes when it was last observed.
+    pub memory_size: u64,
+    /// Largest size the plugin's linear memory has been observed at.
+    pub peak_memory_size: u64,
+    /// Size of the plugin's client call output buffer, if the plugin reports it.
+    pub client_call_output_buffer_size: Option<u32>,
+    /// Size of the plugin's host call input buffer, if the plugin reports it.
+    pub host_call_input_buffer_size: Option<u32>,
+}
+
+impl MemoryStats {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Records the current size of the plugin's linear memory, updating the peak.
+    pub fn observe_memory_size(&mut self, size: u64) {
+        self.memory_size = size;
+        self.peak_memory_size = self.peak_memory_size.max(size);
+    }
+
+    /// Records the packed result of a call to the `plugitin_buffer_sizes` export.
+    pub fn set_buffer_sizes(&mut self, packed: u64) {
+        let (client_call_output, host_call_input) = unpack_buffer_sizes(packed);
+        self.client_call_output_buffer_size = Some(client_call_output);
+        self.host_call_input_buffer_size = Some(host_call_input);
+    }
+}
+
+/// Timers scheduled by a plugin through `Host::schedule`. The host records each call to
+/// the `plugitin_host_schedule` import here and periodically calls `plugitin_on_timer` for
+/// every token returned by `take_due`.
+#[derive(Clone, Debug, Default)]
+pub struct TimerQueue {
+    // Ordered by deadline, then by scheduling order so that timers with the same deadline
+    // fire in the order they were scheduled.
+    timers: BinaryHeap<Reverse<(Instant, u64, u64)>>,
+    next_sequence: u64,
+}
+
+impl TimerQueue {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Services a call to the `plugitin_host_schedule` import made at time `now`.
+    pub fn schedule(&mut self, now: Instant, delay_nanos: u64, token: u64) {
+        let deadline = now.checked_add(Duration::from_nanos(delay_nanos))
+            .unwrap_or_else(|| now + Duration::from_secs(u32::MAX as u64));
+        self.timers.push(Reverse((deadline, self.next_sequence, token)));
+        self.next_sequence += 1;
+    }
+
+    /// Returns the earliest deadline of any pending timer, so the host knows how long it
+    /// may sleep.
+    pub fn next_deadline(&self) -> Option<Instant> {
+        self.timers.peek().map(|Reverse((deadline, _, _))| *deadline)
+    }
+
+    /// Removes and returns the tokens of all timers whose deadline is at or before `now`,
+    /// in the order they should fire.
+    pub fn take_due(&mut self, now: Instant) -> Vec<u64> {
+        let mut due = Vec::new();
+        while let Some(Reverse((deadline, _, token))) = self.timers.peek() {
+            if *deadline > now {
+                break;
+            }
+            due.push(*token);
+            self.timers.pop();
+        }
+        due
+    }
+
+    /// Returns the number of pending timers.
+    pub fn len(&self) -> usize {
+        self.timers.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.timers.is_empty()
+    }
+}
+
+/// Limits on how much load a single plugin instance may put on the host, so that one
+/// misbehaving plugin cannot starve the others of host resources. The default limits
+/// allow everything.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct RateLimits {
+    /// Client calls the plugin may receive per second, averaged over one second so that
+    /// short bursts are allowed. A limit of zero refuses every call.
+    pub calls_per_second: Option<u32>,
+    /// Host calls the plugin may make while handling a single client call.
+    pub host_calls_per_call: Option<u32>,
+    /// Total size in bytes of the host call inputs the plugin may send while handling a
+    /// single client call.
+    pub host_call_bytes_per_call: Option<u64>,
+}
+
+/// Enforces `RateLimits` for one plugin instance. The host calls `begin_call` before each
+/// call into the plugin and `host_call` whenever the plugin calls back into the host, and
+/// refuses the call when either returns `PlugitinError::RateLimited`. Refused host calls
+/// are answered with `abi::HOST_CALL_REFUSED`. Hosts which would rather apply backpressure
+/// than refuse client calls can wait until `available_at` first.
+#[derive(Clone, Debug)]
+pub struct RateLimiter {
+    limits: RateLimits,
+    // Token bucket for client calls, holding at most one second's worth of calls.
+    tokens: f64,
+    last_refill: Option<Instant>,
+    host_calls: u32,
+    host_call_bytes: u64,
+}
+
+impl RateLimiter {
+    pub fn new(limits: RateLimits) -> Self {
+        Self {
+            limits,
+            tokens: limits.calls_per_second.unwrap_or(0) as f64,
+            last_refill: None,
+            host_calls: 0,
+            host_call_bytes: 0,
+        }
+    }
+
+    /// Accounts for a call into the plugin made at time `now`, and resets the per-call host
+    /// call budget.
+    pub fn begin_call(&mut self, now: Instant) -> Result<(), PlugitinError> {
+        self.host_calls = 0;
+        self.host_call_bytes = 0;
+        let rate = match self.limits.calls_per_second {
+            Some(rate) => rate,
+            None => return Ok(()),
+        };
+        self.refill(now, rate);
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(PlugitinError::RateLimited { retry_after: self.time_to_next_token(rate) })
+        }
+    }
+
+    /// Accounts for a call with an input of `input_len` bytes which the plugin made to the
+    /// host while handling the current call.
+    pub fn host_call(&mut self, input_len: u64) -> Result<(), PlugitinError> {
+        self.host_calls = self.host_calls.saturating_add(1);
+        self.host_call_bytes = self.host_call_bytes.saturating_add(input_len);
+        let too_many_calls = self.limits.host_calls_per_call
+            .is_some_and(|limit| self.host_calls > limit);
+        let too_many_bytes = self.limits.host_call_bytes_per_call
+            .is_some_and(|limit| self.host_call_bytes > limit);
+        if too_many_calls || too_many_bytes {
+            Err(PlugitinError::RateLimited { retry_after: None })
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Returns the earliest time at or after `now` at which `begin_call` will succeed, or
+    /// `None` if it never will because the limit is zero.
+    pub fn available_at(&mut self, now: Instant) -> Option<Instant> {
+        let rate = match self.limits.calls_per_second {
+            Some(rate) => rate,
+            None => return Some(now),
+        };
+        self.refill(now, rate);
+        if self.tokens >= 1.0 {
+            Some(now)
+        } else {
+            now.checked_add(self.time_to_next_token(rate)?)
+        }
+    }
+
+    // Returns how long until the bucket holds a whole token, or None if it never will.
+    fn time_to_next_token(&self, rate: u32) -> Option<Duration> {
+        if rate == 0 {
+            return None;
+        }
+        Duration::try_from_secs_f64((1.0 - self.tokens) / rate as f64).ok()
+    }
+
+    fn refill(&mut self, now: Instant, rate: u32) {
+        if let Some(last_refill) = self.last_refill {
+            let elapsed = now.saturating_duration_since(last_refill).as_secs_f64();
+            self.tokens = (self.tokens + elapsed * rate as f64).min(rate as f64);
+        }
+        self.last_refill = Some(now);
+    }
+}
+
+/// Cumulative resource usage of one plugin instance, so that multi-tenant hosts can bill
+/// or throttle tenants. The host records each call into the plugin with `record_call` and
+/// each call the plugin makes back into the host with `record_host_call`. Fuel is
+/// metered by the wasm runtime, so hosts which enable it report consumption with
+/// `record_fuel`. Bytes are counted from the host's point of view: `bytes_sent` were
+/// written into the plugin and `bytes_received` were read out of it.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Usage {
+    pub calls: u64,
+    pub host_calls: u64,
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+    pub cpu_time: Duration,
+    pub fuel: u64,
+}
+
+impl Usage {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Records a call into the plugin which took `cpu_time`, including any host calls it
+    /// made, with an input of `input_len` bytes and an output of `output_len` bytes.
+    pub fn record_call(&mut self, cpu_time: Duration, input_len: u64, output_len: u64) {
+        self.calls += 1;
+        self.cpu_time += cpu_time;
+        self.bytes_sent += input_len;
+        self.bytes_received += output_len;
+    }
+
+    /// Records a call the plugin made to the host with an input of `input_len` bytes and an
+    /// output of `output_len` bytes.
+    pub fn record_host_call(&mut self, input_len: u64, output_len: u64) {
+        self.host_calls += 1;
+        self.bytes_received += input_len;
+        self.bytes_sent += output_len;
+    }
+
+    /// Records fuel consumed by the plugin.
+    pub fn record_fuel(&mut self, fuel: u64) {
+        self.fuel += fuel;
+    }
+
+    /// Returns the usage accumulated so far and starts counting again from zero, for
+    /// example at the end of a billing period.
+    pub fn reset(&mut self) -> Usage {
+        std::mem::take(self)
+    }
+}
+
+/// How a host retries calls into a plugin which failed for transient reasons, such as
+/// `PlugitinError::RateLimited` or a timeout reported by the wasm runtime. Retries back off
+/// exponentially. The default policy never retries.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RetryPolicy {
+    /// Total number of attempts, including the first.
+    pub max_attempts: u32,
+    /// Delay before the first retry. Each further retry waits twice as long as the last.
+    pub initial_backoff: Duration,
+    /// Longest delay between two attempts.
+    pub max_backoff: Duration,
+}
+
+impl RetryPolicy {
+    /// A policy which never retries.
+    pub fn none() -> Self {
+        Self {
+            max_attempts: 1,
+            initial_backoff: Duration::from_millis(0),
+            max_backoff: Duration::from_millis(0),
+        }
+    }
+
+    /// Returns how long to wait before retrying after attempt number `attempt`, counting
+    /// from 1, failed with a transient error, or `None` if no attempts remain.
+    pub fn backoff(&self, attempt: u32) -> Option<Duration> {
+        if attempt >= self.max_attempts {
+            return None;
+        }
+        let factor = 1u32.checked_shl(attempt.saturating_sub(1)).unwrap_or(u32::MAX);
+        let backoff = self.initial_backoff.checked_mul(factor).unwrap_or(self.max_backoff);
+        Some(backoff.min(self.max_backoff))
+    }
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self::none()
+    }
+}
+
+/// When a `CircuitBreaker` quarantines a plugin.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CircuitBreakerPolicy {
+    /// Number of consecutive failed calls after which the plugin is quarantined.
+    pub failure_threshold: u32,
+    /// How long the plugin stays quarantined before another call is attempted.
+    pub cooldown: Duration,
+}
+
+/// Quarantines a plugin which keeps failing, so that it cannot take down the host's
+/// request path. The host keeps one breaker per plugin instance, asks `allow` before each
+/// call and reports the outcome with `record_success` or `record_failure`. Once the
+/// cooldown has passed calls are let through again, and a single further failure restarts
+/// the quarantine.
+#[derive(Clone, Debug)]
+pub struct CircuitBreaker {
+    policy: CircuitBreakerPolicy,
+    consecutive_failures: u32,
+    open_until: Option<Instant>,
+}
+
+impl CircuitBreaker {
+    pub fn new(policy: CircuitBreakerPolicy) -> Self {
+        Self {
+            policy,
+            consecutive_failures: 0,
+            open_until: None,
+        }
+    }
+
+    /// Checks whether a call may be made at time `now`, failing with
+    /// `PlugitinError::Quarantined` while the plugin is quarantined.
+    pub fn allow(&self, now: Instant) -> Result<(), PlugitinError> {
+        match self.open_until {
+            Some(open_until) if open_until > now => Err(PlugitinError::Quarantined {
+                retry_after: open_until - now,
+            }),
+            _ => Ok(()),
+        }
+    }
+
+    /// Records a call which succeeded, ending any quarantine.
+    pub fn record_success(&mut self) {
+        self.consecutive_failures = 0;
+        self.open_until = None;
+    }
+
+    /// Records a call which failed at time `now`.
+    pub fn record_failure(&mut self, now: Instant) {
+        self.consecutive_failures = self.consecutive_failures.saturating_add(1);
+        if self.consecutive_failures >= self.policy.failure_threshold {
+            self.open_until = Some(now + self.policy.cooldown);
+        }
+    }
+
+    /// Returns whether the plugin is quarantined at time `now`.
+    pub fn is_open(&self, now: Instant) -> bool {
+        self.allow(now).is_err()
+    }
+}
+
+/// Which way a call recorded in an `ActivityLog` crossed the boundary.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Direction {
+    /// The host called into the plugin.
+    ClientCall,
+    /// The plugin called back into the host.
+    HostCall,
+}
+
+/// How much of each payload an `ActivityLog` keeps.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Capture {
+    /// Keep a copy of every payload.
+    Payloads,
+    /// Keep only the length and a 64-bit FNV-1a digest of every payload, which is enough to
+    /// tell whether two calls exchanged the same messages.
+    Digests,
+}
+
+/// A payload recorded by an `ActivityLog`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Payload {
+    Bytes(Vec<u8>),
+    Digest { len: u64, digest: u64 },
+}
+
+impl Payload {
+    /// Returns the length of the payload in bytes.
+    pub fn len(&self) -> u64 {
+        match self {
+            Payload::Bytes(bytes) => bytes.len() as u64,
+            Payload::Digest { len, .. } => *len,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// A call recorded by an `ActivityLog`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Activity {
+    pub direction: Direction,
+    /// Export or import through which the call was made, for example
+    /// `abi::CLIENT_CALL_EXPORT` or `abi::HOST_KV_GET_IMPORT`.
+    pub method: String,
+    pub started: SystemTime,
+    pub duration: Duration,
+    pub input: Payload,
+    pub output: Payload,
+}
+
+/// Recent traffic across the boundary of one plugin instance, for debugging misbehaving
+/// plugins in production. The host records each call into the plugin and each call the
+/// plugin makes back into the host with `record`. Only the most recent `capacity` calls are
+/// kept, so the log can stay enabled indefinitely.
+#[derive(Clone, Debug)]
+pub struct ActivityLog {
+    capture: Capture,
+    capacity: usize,
+    entries: VecDeque<Activity>,
+}
+
+impl ActivityLog {
+    pub fn new(capacity: usize, capture: Capture) -> Self {
+        Self {
+            capture,
+            capacity,
+            entries: VecDeque::with_capacity(capacity),
+        }
+    }
+
+    /// Records a call made through `method` which started at `started` and took
+    /// `duration`, evicting the oldest call if the log is full.
+    pub fn record(
+        &mut self,
+        direction: Direction,
+        method: &str,
+        started: SystemTime,
+        duration: Duration,
+        input: &[u8],
+        output: &[u8])
+    {
+        if self.capacity == 0 {
+            return;
+        }
+        if self.entries.len() == self.capacity {
+            self.entries.pop_front();
+        }
+        let input = self.capture_payload(input);
+        let output = self.capture_payload(output);
+        self.entries.push_back(Activity {
+            direction,
+            method: method.to_owned(),
+            started,
+            duration,
+            input,
+            output,
+        });
+    }
+
+    /// Returns the recorded calls, oldest first.
+    pub fn recent_activity(&self) -> impl Iterator<Item = &Activity> {
+        self.entries.iter()
+    }
+
+    /// Removes and returns the recorded calls, oldest first, for example to write them to
+    /// a file.
+    pub fn drain(&mut self) -> Vec<Activity> {
+        self.entries.drain(..).collect()
+    }
+
+    /// Returns the number of recorded calls.
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    fn capture_payload(&self, payload: &[u8]) -> Payload {
+        match self.capture {
+            Capture::Payloads => Payload::Bytes(payload.to_vec()),
+            Capture::Digests => Payload::Digest {
+                len: payload.len() as u64,
+                digest: fnv1a(payload),
+            },
+        }
+    }
+}
+
+// 64-bit FNV-1a. See http://www.isthe.com/chongo/tech/comp/fnv/.
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xCBF2_9CE4_8422_2325, |hash, byte| {
+        (hash ^ *byte as u64).wrapping_mul(0x0000_0100_0000_01B3)
+    })
+}
+
+/// Replays a session recorded by an `ActivityLog` with `Capture::Payloads` against a
+/// plugin, so that a bug seen in production can be reproduced offline. The test makes each
+/// client call returned by `next_client_call`, answers every host call the plugin makes
+/// with `host_call`, and hands the plugin's output to `finish_client_call`. Host calls are
+/// answered with the recorded outputs, so the plugin observes the same host as in the
+/// recorded session, and any call which differs from the recording fails with
+/// `PlugitinError::Replay`. Hosts which record imports such as `plugitin_host_now`, whose
+/// results are not buffers, should record the little-endian bytes of the result.
+#[derive(Clone, Debug)]
+pub struct Replay {
+    activities: Vec<Activity>,
+    // Index of the next recorded call to replay.
+    position: usize,
+    // Index of the client call being replayed, if any.
+    current: Option<usize>,
+}
+
+/// A client call to make while replaying a session.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ReplayCall {
+    pub method: String,
+    pub input: Vec<u8>,
+}
+
+impl Replay {
+    /// Prepares a session for replay. Fails if any call was recorded without its payloads.
+    pub fn new<I: IntoIterator<Item = Activity>>(activities: I) -> Result<Self, PlugitinError> {
+        let activities: Vec<Activity> = activities.into_iter().collect();
+        for activity in activities.iter() {
+            let recorded = |payload: &Payload| matches!(payload, Payload::Bytes(_));
+            if !recorded(&activity.input) || !recorded(&activity.output) {
+                return Err(PlugitinError::Replay(format!(
+                    "call to `{}` was recorded without its payloads", activity.method)));
+            }
+        }
+        Ok(Self { activities, position: 0, current: None })
+    }
+
+    /// Returns the next client call to make, or `None` once the session is complete. Host
+    /// calls recorded after the last client call are discarded, since the client call they
+    /// belong to never completed.
+    pub fn next_client_call(&mut self) -> Result<Option<ReplayCall>, PlugitinError> {
+        if self.current.is_some() {
+            return Err(PlugitinError::Replay(
+                "next client call requested before the current one finished".to_owned()));
+        }
+        // Host calls are recorded as they complete, and therefore before the client call
+        // during which they were made.
+        let index = self.activities[self.position..].iter()
+            .position(|activity| activity.direction == Direction::ClientCall)
+            .map(|offset| self.position + offset);
+        Ok(index.map(|index| {
+            self.current = Some(index);
+            let activity = &self.activities[index];
+            ReplayCall {
+                method: activity.method.clone(),
+                input: payload_bytes(&activity.input).to_vec(),
+            }
+        }))
+    }
+
+    /// Answers a call the plugin made to the host through `method` with the recorded
+    /// output.
+    pub fn host_call(&mut self, method: &str, input: &[u8]) -> Result<Vec<u8>, PlugitinError> {
+        let current = self.current.ok_or_else(|| PlugitinError::Replay(format!(
+            "plugin called `{}` outside of a client call", method)))?;
+        if self.position == current {
+            return Err(PlugitinError::Replay(format!(
+                "plugin made an unexpected call to `{}`", method)));
+        }
+        let activity = &self.activities[self.position];
+        if activity.method != method {
+            return Err(PlugitinError::Replay(format!(
+                "plugin called `{}` where `{}` was recorded", method, activity.method)));
+        }
+        if payload_bytes(&activity.input) != input {
+            return Err(PlugitinError::Replay(format!(
+                "plugin called `{}` with a different input than recorded", method)));
+        }
+        self.position += 1;
+        Ok(payload_bytes(&activity.output).to_vec())
+    }
+
+    /// Completes the current client call, checking that the plugin made every recorded host
+    /// call and produced the recorded output.
+    pub fn finish_client_call(&mut self, output: &[u8]) -> Result<(), PlugitinError> {
+        let current = self.current.take().ok_or_else(|| PlugitinError::Replay(
+            "no client call is being replayed".to_owned()))?;
+        // Move past the client call even if it diverged, so that the rest of the session can
+        // still be replayed.
+        let position = std::mem::replace(&mut self.position, current + 1);
+        let activity = &self.activities[current];
+        if position != current {
+            return Err(PlugitinError::Replay(format!(
+                "plugin skipped a recorded call to `{}`", self.activities[position].method)));
+        }
+        if payload_bytes(&activity.output) != output {
+            return Err(PlugitinError::Replay(format!(
+                "call to `{}` produced a different output than recorded", activity.method)));
+        }
+        Ok(())
+    }
+}
+
+fn payload_bytes(payload: &Payload) -> &[u8] {
+    match payload {
+        Payload::Bytes(bytes) => bytes,
+        // Rejected by Replay::new.
+        Payload::Digest { .. } => unreachable!(),
+    }
+}
+
+/// What a `CallbackQueue` does with an item pushed while it is full.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OverflowPolicy {
+    /// Wait until a consumer makes room. The plugin stalls inside its host call.
+    Block,
+    /// Discard the oldest queued item to make room.
+    DropOldest,
+    /// Refuse the item with `PlugitinError::QueueFull`, which the host can pass on to the
+    /// plugin as a refused host call.
+    Error,
+}
+
+/// Bounded queue between the host calls through which a plugin hands items to the host,
+/// such as notifications or output, and the host code consuming them, so that a plugin
+/// producing items faster than they are consumed is held back predictably. Host call
+/// handlers `push` and consumers `pop`. The queue is shared between threads, for example
+/// through an `Arc`.
+#[derive(Debug)]
+pub struct CallbackQueue<T> {
+    capacity: usize,
+    policy: OverflowPolicy,
+    state: Mutex<CallbackQueueState<T>>,
+    // Signalled whenever room is made in the queue or an item is added to it.
+    changed: Condvar,
+}
+
+#[derive(Debug)]
+struct CallbackQueueState<T> {
+    items: VecDeque<T>,
+    dropped: u64,
+}
+
+impl<T> CallbackQueue<T> {
+    /// Creates a queue holding at most `capacity` items, which must be nonzero.
+    pub fn new(capacity: usize, policy: OverflowPolicy) -> Self {
+        assert!(capacity > 0, "callback queue capacity must be nonzero");
+        Self {
+            capacity,
+            policy,
+            state: Mutex::new(CallbackQueueState {
+                items: VecDeque::with_capacity(capacity),
+                dropped: 0,
+            }),
+            changed: Condvar::new(),
+        }
+    }
+
+    // The state is consistent whenever the lock is released, so a thread which panicked
+    // while holding it, such as a consumer whose item handling failed, does not leave the
+    // queue unusable.
+    fn lock(&self) -> MutexGuard<'_, CallbackQueueState<T>> {
+        self.state.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    /// Adds an item to the queue, applying the overflow policy if it is full.
+    pub fn push(&self, item: T) -> Result<(), PlugitinError> {
+        let mut state = self.lock();
+        if state.items.len() >= self.capacity {
+            match self.policy {
+                OverflowPolicy::Block => {
+                    state = self.changed
+                        .wait_while(state, |state| state.items.len() >= self.capacity)
+                        .unwrap_or_else(|poisoned| poisoned.into_inner());
+                }
+                OverflowPolicy::DropOldest => {
+                    state.items.pop_front();
+                    state.dropped += 1;
+                }
+                OverflowPolicy::Error => {
+                    return Err(PlugitinError::QueueFull { capacity: self.capacity });
+                }
+            }
+        }
+        state.items.push_back(item);
+        self.changed.notify_all();
+        Ok(())
+    }
+
+    /// Removes and returns the oldest item, or `None` if the queue is empty.
+    pub fn try_pop(&self) -> Option<T> {
+        let item = self.lock().items.pop_front();
+        if item.is_some() {
+            self.changed.notify_all();
+        }
+        item
+    }
+
+    /// Removes and returns the oldest item, waiting for one if the queue is empty.
+    pub fn pop(&self) -> T {
+        let mut state = self.changed
+            .wait_while(self.lock(), |state| state.items.is_empty())
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        let item = state.items.pop_front().unwrap();
+        self.changed.notify_all();
+        item
+    }
+
+    /// Returns the number of queued items.
+    pub fn len(&self) -> usize {
+        self.lock().items.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Returns the number of items discarded under `OverflowPolicy::DropOldest`.
+    pub fn dropped(&self) -> u64 {
+        self.lock().dropped
+    }
+}
+
+/// Memory allocated in a plugin on behalf of the host, as tracked by an `AllocationAudit`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Allocation {
+    pub ptr: u32,
+    pub size: u32,
+    pub align: u32,
+    /// Where the host allocated the memory, for example `"client_call input"`.
+    pub site: &'static str,
+}
+
+/// Tracks the memory a host allocates in one plugin instance, to find leaked transfer
+/// buffers, which is most useful when a plugin overrides the default allocator. The host
+/// reports every call it makes to `plugitin_alloc`, `plugitin_alloc_uninit` or
+/// `plugitin_alloc_shared_data` with `record_alloc` and every call to `plugitin_dealloc`
+/// with `record_dealloc`. Memory whose ownership passes to the plugin, such as host call
+/// results, shared datasets and inputs the plugin frees itself, is reported with
+/// `record_transfer`. Strict hosts call `check_leaks` before tearing the instance down and
+/// fail if it reports a leak. Zero-byte allocations occupy no memory and share dangling
+/// pointers, so they are not tracked.
+#[derive(Clone, Debug, Default)]
+pub struct AllocationAudit {
+    // Keyed by pointer, so that overlapping allocations can be found from their neighbours.
+    outstanding: BTreeMap<u32, Allocation>,
+}
+
+impl AllocationAudit {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Records memory returned by one of the plugin's allocation exports, failing if it
+    /// overlaps memory which is still live, which means the plugin's allocator is broken.
+    pub fn record_alloc(&mut self, ptr: u32, size: u32, align: u32, site: &'static str)
+        -> Result<(), PlugitinError>
+    {
+        if size == 0 {
+            return Ok(());
+        }
+        let end = ptr as u64 + size as u64;
+        let overlaps_previous = self.outstanding.range(..=ptr).next_back()
+            .is_some_and(|(_, previous)| previous.ptr as u64 + previous.size as u64 > ptr as u64);
+        let overlaps_next = self.outstanding.range(ptr..).next()
+            .is_some_and(|(_, next)| (next.ptr as u64) < end);
+        if overlaps_previous || overlaps_next {
+            return Err(PlugitinError::OverlappingAllocation { ptr, size });
+        }
+        self.outstanding.insert(ptr, Allocation { ptr, size, align, site });
+        Ok(())
+    }
+
+    /// Records a call to `plugitin_dealloc`, failing if the memory was not allocated with
+    /// the same size and alignment.
+    pub fn record_dealloc(&mut self, ptr: u32, size: u32, align: u32)
+        -> Result<(), PlugitinError>
+    {
+        if size == 0 {
+            return Ok(());
+        }
+        match self.outstanding.get(&ptr) {
+            Some(allocation) if allocation.size == size && allocation.align == align => {
+                self.outstanding.remove(&ptr);
+                Ok(())
+            }
+            _ => Err(PlugitinError::InvalidDealloc { ptr, size, align }),
+        }
+    }
+
+    /// Records that ownership of the `size` bytes at `ptr` passed to the plugin.
+    pub fn record_transfer(&mut self, ptr: u32, size: u32) {
+        if size != 0 {
+            self.outstanding.remove(&ptr);
+        }
+    }
+
+    /// Returns the memory which has been neither freed nor transferred, in address order.
+    pub fn outstanding(&self) -> Vec<Allocation> {
+        self.outstanding.values().copied().collect()
+    }
+
+    /// Fails with `PlugitinError::Leaked` if any memory is outstanding.
+    pub fn check_leaks(&self) -> Result<(), PlugitinError> {
+        if self.outstanding.is_empty() {
+            return Ok(());
+        }
+        Err(PlugitinError::Leaked {
+            allocations: self.outstanding.len(),
+            bytes: self.outstanding.values().map(|allocation| allocation.size as u64).sum(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::abi::pack_buffer_desc;
+
+    #[test]
+    fn buffer_range_rejects_descriptors_outside_memory() {
+        assert_eq!(buffer_range(16, pack_buffer_desc(0, 16)), Ok(0..16));
+        assert_eq!(buffer_range(16, pack_buffer_desc(12, 4)), Ok(12..16));
+        assert_eq!(buffer_range(16, pack_buffer_desc(16, 0)), Ok(16..16));
+        for (ptr, len) in [(12, 5), (17, 0), (0, u32::MAX)] {
+            assert_eq!(
+                buffer_range(16, pack_buffer_desc(ptr, len)),
+                Err(PlugitinError::InvalidBufferDescriptor {
+                    desc: BufferDesc { ptr, len },
+                    memory_len: 16,
+                }));
+        }
+
+        // The end of this buffer does not fit in 32 bits.
+        let memory_len = u32::MAX as usize;
+        assert!(buffer_range(memory_len, pack_buffer_desc(u32::MAX, 1)).is_err());
+        assert!(buffer_range(memory_len, pack_buffer_desc(u32::MAX, u32::MAX)).is_err());
+        assert!(buffer_range(memory_len, pack_buffer_desc(u32::MAX - 1, 1)).is_ok());
+    }
+
+    #[test]
+    fn read_and_write_buffer_borrow_described_memory() {
+        let mut memory: Vec<u8> = (0..8).collect();
+        assert_eq!(read_buffer(&memory, pack_buffer_desc(2, 3)), Ok(&[2, 3, 4][..]));
+        assert_eq!(read_buffer(&memory, pack_buffer_desc(5, 3)), Ok(&[5, 6, 7][..]));
+        assert!(read_buffer(&memory, pack_buffer_desc(5, 4)).is_err());
+
+        write_buffer(&mut memory, pack_buffer_desc(6, 2)).unwrap().copy_from_slice(&[9, 9]);
+        assert_eq!(memory, [0, 1, 2, 3, 4, 5, 9, 9]);
+        assert!(write_buffer(&mut memory, pack_buffer_desc(7, 2)).is_err());
+    }
+
+    #[test]
+    fn message_limits_enforce_sizes() {
+        let limits = MessageLimits { max_incoming: 4, max_outgoing: 8 };
+        assert!(limits.check_incoming(4).is_ok());
+        assert_eq!(
+            limits.check_incoming(5),
+            Err(PlugitinError::MessageTooLarge { size: 5, limit: 4 }));
+        assert!(limits.check_outgoing(8).is_ok());
+        assert_eq!(
+            limits.check_outgoing(9),
+            Err(PlugitinError::MessageTooLarge { size: 9, limit: 8 }));
+
+        let memory = [7u8; 16];
+        assert_eq!(limits.read_incoming(&memory, pack_buffer_desc(12, 4)), Ok(&[7; 4][..]));
+        assert_eq!(
+            limits.read_incoming(&memory, pack_buffer_desc(0, 5)),
+            Err(PlugitinError::MessageTooLarge { size: 5, limit: 4 }));
+        assert!(matches!(
+            limits.read_incoming(&memory, pack_buffer_desc(14, 4)),
+            Err(PlugitinError::InvalidBufferDescriptor { .. })));
+    }
+
+    #[derive(Default)]
+    struct RecordingOutput {
+        chunks: Vec<(String, OutputStream, String)>,
+    }
+
+    impl OutputHandler for RecordingOutput {
+        fn output(&mut self, plugin: &str, stream: OutputStream, text: &str) {
+            self.chunks.push((plugin.to_string(), stream, text.to_string()));
+        }
+    }
+
+    #[test]
+    fn output_is_validated_and_decoded_lossily() {
+        let mut handler = RecordingOutput::default();
+        let limits = MessageLimits { max_incoming: 4, max_outgoing: 0 };
+        let memory = b"hi\n\xf0!";
+        handle_output(&mut handler, "p", memory, STDOUT_STREAM, pack_buffer_desc(0, 3), &limits)
+            .unwrap();
+        handle_output(&mut handler, "p", memory, STDERR_STREAM, pack_buffer_desc(3, 2), &limits)
+            .unwrap();
+        assert_eq!(handler.chunks, [
+            ("p".to_string(), OutputStream::Stdout, "hi\n".to_string()),
+            ("p".to_string(), OutputStream::Stderr, "\u{fffd}!".to_string()),
+        ]);
+
+        assert_eq!(
+            handle_output(&mut handler, "p", memory, 7, pack_buffer_desc(0, 1), &limits),
+            Err(PlugitinError::UnknownOutputStream(7)));
+        assert!(handle_output(
+            &mut handler, "p", memory, STDOUT_STREAM, pack_buffer_desc(0, 5), &limits).is_err());
+        assert!(handle_output(
+            &mut handler, "p", memory, STDOUT_STREAM, pack_buffer_desc(4, 2), &limits).is_err());
+        assert_eq!(handler.chunks.len(), 2);
+    }
+
+    #[test]
+    fn kv_handlers_read_plugin_memory() {
+        let mut backend = MemoryKvBackend::new();
+        let limits = MessageLimits { max_incoming: 8, max_outgoing: 5 };
+        let mut memory = [0u8; 32];
+        memory[..3].copy_from_slice(b"key");
+        memory[8..13].copy_from_slice(b"value");
+        let key = pack_buffer_desc(0, 3);
+
+        handle_kv_set(&mut backend, "a", &memory, key, pack_buffer_desc(8, 5), &limits)
+            .unwrap();
+        let dest = pack_buffer_desc(16, 8);
+        assert_eq!(handle_kv_get(&mut backend, "a", &mut memory, key, dest, &limits), Ok(5));
+        assert_eq!(&memory[16..24], b"value\0\0\0");
+        assert_eq!(
+            handle_kv_get(&mut backend, "b", &mut memory, key, dest, &limits),
+            Ok(KV_ABSENT));
+
+        // A destination which is too small is left untouched, and the length is returned
+        // so that the plugin can retry with a larger one.
+        let small = pack_buffer_desc(24, 4);
+        assert_eq!(handle_kv_get(&mut backend, "a", &mut memory, key, small, &limits), Ok(5));
+        assert_eq!(memory[24..28], [0; 4]);
+
+        assert!(handle_kv_set(
+            &mut backend, "a", &memory, key, pack_buffer_desc(8, 9), &limits).is_err());
+        assert!(handle_kv_set(
+            &mut backend, "a", &memory, key, pack_buffer_desc(30, 4), &limits).is_err());
+        assert!(handle_kv_get(
+            &mut backend, "a", &mut memory, key, pack_buffer_desc(30, 4), &limits).is_err());
+
+        let strict = MessageLimits { max_outgoing: 4, ..limits };
+        assert_eq!(
+            handle_kv_get(&mut backend, "a", &mut memory, key, dest, &strict),
+            Err(PlugitinError::MessageTooLarge { size: 5, limit: 4 }));
+
+        assert_eq!(handle_kv_delete(&mut backend, "b", &memory, key, &limits), Ok(0));
+        assert_eq!(handle_kv_delete(&mut backend, "a", &memory, key, &limits), Ok(1));
+        assert_eq!(handle_kv_delete(&mut backend, "a", &memory, key, &limits), Ok(0));
+        assert!(handle_kv_delete(&mut backend, "a", &memory, pack_buffer_desc(0, 9), &limits)
+            .is_err());
+    }
+
+    #[test]
+    fn versioned_messages_round_trip() {
+        let limits = MessageLimits::unlimited();
+        let message = write_versioned_input(&"hi".to_string(), 3, &limits).unwrap();
+        let (version, payload) = split_envelope(&message).unwrap();
+        assert_eq!(version, 3);
+        assert_eq!(payload, &bincode::serialize("hi").unwrap()[..]);
+
+        let output: String = read_versioned_output(&message, 3, |_, _| unreachable!()).unwrap();
+        assert_eq!(output, "hi");
+        let migrated: String = read_versioned_output(&message, 4, |version, payload| {
+            assert_eq!(version, 3);
+            Ok(format!("{}", payload.len()))
+        }).unwrap();
+        assert_eq!(migrated, "10");
+
+        assert_eq!(
+            read_versioned_output::<String, _>(&[3, 0, 0], 3, |_, _| unreachable!()),
+            Err(PlugitinError::InvalidEnvelope));
+        let limits = MessageLimits { max_incoming: 0, max_outgoing: 13 };
+        assert_eq!(
+            write_versioned_input(&"hi".to_string(), 3, &limits),
+            Err(PlugitinError::MessageTooLarge { size: 14, limit: 13 }));
+    }
+
+    #[test]
+    fn envelope_header_splits_from_payload() {
+        let mut message = [0xaa; 6];
+        write_envelope_header(&mut message, 0x0102_0304);
+        assert_eq!(message, [4, 3, 2, 1, 0xaa, 0xaa]);
+        assert_eq!(split_envelope(&message), Some((0x0102_0304, &[0xaa, 0xaa][..])));
+        assert_eq!(split_envelope(&message[..ENVELOPE_HEADER_LEN]), Some((0x0102_0304, &[][..])));
+        assert_eq!(split_envelope(&message[..3]), None);
+    }
+
+    fn http_policy() -> HttpPolicy {
+        HttpPolicy {
+            allowed_hosts: vec!["api.example.com".to_string(), "*.example.org".to_string()],
+            allowed_methods: vec!["GET".to_string()],
+            max_request_body: 16,
+            max_response_body: 16,
+        }
+    }
+
+    #[test]
+    fn http_policy_checks_parsed_host() {
+        let policy = http_policy();
+        let target = policy.check_request(&HttpRequest::get("https://API.example.com/a")).unwrap();
+        assert_eq!(target.host, "api.example.com");
+        assert!(policy.check_request(&HttpRequest::get("http://cdn.example.org/")).is_ok());
+        for url in [
+            "https://evil.com\\@api.example.com/",
+            "file://api.example.com/etc/passwd",
+            "https://example.org/",
+            "https://evil.com/",
+        ] {
+            assert!(policy.check_request(&HttpRequest::get(url)).is_err(), "{}", url);
+        }
+    }
+
+    // A FileKvBackend in a fresh directory, which is removed when the test ends.
+    struct TempKv {
+        backend: FileKvBackend,
+        root: PathBuf,
+    }
+
+    impl TempKv {
+        fn new(name: &str) -> Self {
+            let root = std::env::temp_dir()
+                .join(format!("plugitin-kv-{}-{}", name, std::process::id()));
+            let _ = std::fs::remove_dir_all(&root);
+            Self { backend: FileKvBackend::new(&root), root }
+        }
+    }
+
+    impl Drop for TempKv {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.root);
+        }
+    }
+
+    #[test]
+    fn file_kv_backend_stores_values() {
+        let mut kv = TempKv::new("values");
+        let backend = &mut kv.backend;
+        assert_eq!(backend.get("a", b"key").unwrap(), None);
+        backend.set("a", b"key", b"one").unwrap();
+        backend.set("a", b"key", b"two").unwrap();
+        assert_eq!(backend.get("a", b"key").unwrap(), Some(b"two".to_vec()));
+        assert_eq!(backend.get("b", b"key").unwrap(), None);
+        assert!(backend.delete("a", b"key").unwrap());
+        assert!(!backend.delete("a", b"key").unwrap());
+        assert_eq!(backend.get("a", b"key").unwrap(), None);
+    }
+
+    #[test]
+    fn file_kv_backend_handles_empty_and_long_keys() {
+        let mut kv = TempKv::new("keys");
+        let backend = &mut kv.backend;
+        let long_key = vec![0xAB; 1000];
+        let prefix = &long_key[..KV_PATH_SEGMENT_LEN];
+        assert_eq!(backend.get("", b"").unwrap(), None);
+        backend.set("", b"", b"empty").unwrap();
+        backend.set("", &long_key, b"long").unwrap();
+        backend.set("", prefix, b"prefix").unwrap();
+        assert_eq!(backend.get("", b"").unwrap(), Some(b"empty".to_vec()));
+        assert_eq!(backend.get("", &long_key).unwrap(), Some(b"long".to_vec()));
+        assert_eq!(backend.get("", prefix).unwrap(), Some(b"prefix".to_vec()));
+        assert!(backend.delete("", b"").unwrap());
+        assert_eq!(backend.get("", &long_key).unwrap(), Some(b"long".to_vec()));
+    }
+
+    fn activity(direction: Direction, method: &str, input: &[u8], output: &[u8]) -> Activity {
+        Activity {
+            direction,
+            method: method.to_string(),
+            started: UNIX_EPOCH,
+            duration: Duration::from_millis(1),
+            input: Payload::Bytes(input.to_vec()),
+            output: Payload::Bytes(output.to_vec()),
+        }
+    }
+
+    // Two client calls, the first of which made one host call.
+    fn session() -> Vec<Activity> {
+        vec![
+            activity(Direction::HostCall, "plugitin_host_call", b"ping", b"pong"),
+            activity(Direction::ClientCall, "plugitin_client_call", b"first", b"one"),
+            activity(Direction::ClientCall, "plugitin_client_call", b"second", b"two"),
+        ]
+    }
+
+    fn client_call(input: &[u8]) -> Option<ReplayCall> {
+        Some(ReplayCall { method: "plugitin_client_call".to_string(), input: input.to_vec() })
+    }
+
+    #[test]
+    fn replay_reproduces_session() {
+        let mut replay = Replay::new(session()).unwrap();
+        assert_eq!(replay.next_client_call().unwrap(), client_call(b"first"));
+        assert_eq!(replay.host_call("plugitin_host_call", b"ping").unwrap(), b"pong");
+        replay.finish_client_call(b"one").unwrap();
+        assert_eq!(replay.next_client_call().unwrap(), client_call(b"second"));
+        replay.finish_client_call(b"two").unwrap();
+        assert_eq!(replay.next_client_call().unwrap(), None);
+    }
+
+    #[test]
+    fn replay_reports_divergent_host_calls() {
+        let mut replay = Replay::new(session()).unwrap();
+        replay.next_client_call().unwrap();
+        assert!(replay.host_call("plugitin_host_now", b"ping").is_err());
+        assert!(replay.host_call("plugitin_host_call", b"pang").is_err());
+        assert_eq!(replay.host_call("plugitin_host_call", b"ping").unwrap(), b"pong");
+        assert!(replay.host_call("plugitin_host_call", b"ping").is_err());
+        assert!(replay.finish_client_call(b"uno").is_err());
+        assert_eq!(replay.next_client_call().unwrap(), client_call(b"second"));
+    }
+
+    #[test]
+    fn replay_moves_on_after_skipped_host_calls() {
+        let mut replay = Replay::new(session()).unwrap();
+        replay.next_client_call().unwrap();
+        assert!(replay.finish_client_call(b"one").is_err());
+        assert_eq!(replay.next_client_call().unwrap(), client_call(b"second"));
+        replay.finish_client_call(b"two").unwrap();
+        assert_eq!(replay.next_client_call().unwrap(), None);
+    }
+
+    #[test]
+    fn replay_requires_recorded_payloads() {
+        let mut log = ActivityLog::new(4, Capture::Digests);
+        log.record(Direction::ClientCall, "plugitin_client_call", UNIX_EPOCH, Duration::ZERO,
+            b"input", b"output");
+        assert!(Replay::new(log.drain()).is_err());
+
+        let mut replay = Replay::new(Vec::new()).unwrap();
+        assert!(replay.host_call("plugitin_host_call", b"ping").is_err());
+        assert!(replay.finish_client_call(b"").is_err());
+        assert_eq!(replay.next_client_call().unwrap(), None);
+    }
+
+    #[test]
+    fn activity_log_keeps_most_recent_calls() {
+        let mut log = ActivityLog::new(2, Capture::Digests);
+        for input in [b"a", b"b", b"c"] {
+            log.record(Direction::HostCall, "plugitin_host_call", UNIX_EPOCH, Duration::ZERO,
+                input, b"");
+        }
+        let inputs: Vec<u64> = log.recent_activity()
+            .map(|activity| match activity.input {
+                Payload::Digest { digest, .. } => digest,
+                Payload::Bytes(_) => panic!("payload was captured"),
+            })
+            .collect();
+        assert_eq!(inputs, vec![fnv1a(b"b"), fnv1a(b"c")]);
+    }
+
+    #[test]
+    fn allocation_audit_tracks_allocations() {
+        let mut audit = AllocationAudit::new();
+        audit.record_alloc(0x100, 16, 8, "input").unwrap();
+        audit.record_alloc(0x200, 32, 1, "output").unwrap();
+        audit.record_alloc(0x300, 8, 1, "shared data").unwrap();
+        assert_eq!(audit.check_leaks(), Err(PlugitinError::Leaked { allocations: 3, bytes: 56 }));
+
+        audit.record_dealloc(0x100, 16, 8).unwrap();
+        audit.record_transfer(0x300, 8);
+        assert_eq!(audit.outstanding(), vec![Allocation {
+            ptr: 0x200,
+            size: 32,
+            align: 1,
+            site: "output",
+        }]);
+        audit.record_dealloc(0x200, 32, 1).unwrap();
+        assert_eq!(audit.check_leaks(), Ok(()));
+    }
+
+    #[test]
+    fn allocation_audit_rejects_invalid_deallocs() {
+        let mut audit = AllocationAudit::new();
+        audit.record_alloc(0x100, 16, 8, "input").unwrap();
+        for (ptr, size, align) in [(0x100, 8, 8), (0x100, 16, 1), (0x108, 8, 8), (0x400, 4, 1)] {
+            assert_eq!(
+                audit.record_dealloc(ptr, size, align),
+                Err(PlugitinError::InvalidDealloc { ptr, size, align }));
+        }
+        audit.record_dealloc(0x100, 16, 8).unwrap();
+        assert!(audit.record_dealloc(0x100, 16, 8).is_err());
+    }
+
+    #[test]
+    fn allocation_audit_rejects_overlapping_allocations() {
+        let mut audit = AllocationAudit::new();
+        audit.record_alloc(0x100, 16, 8, "input").unwrap();
+        for (ptr, size) in [(0x100, 16), (0x108, 4), (0xF8, 16), (0x10F, 1), (0x80, 0x100)] {
+            assert_eq!(
+                audit.record_alloc(ptr, size, 1, "overlap"),
+                Err(PlugitinError::OverlappingAllocation { ptr, size }));
+        }
+        audit.record_alloc(0xF0, 16, 1, "before").unwrap();
+        audit.record_alloc(0x110, 16, 1, "after").unwrap();
+        assert_eq!(audit.outstanding().len(), 3);
+    }
+
+    #[test]
+    fn allocation_audit_ignores_zero_sized_allocations() {
+        let mut audit = AllocationAudit::new();
+        audit.record_alloc(8, 0, 8, "empty input").unwrap();
+        audit.record_alloc(8, 0, 8, "empty context").unwrap();
+        audit.record_alloc(8, 16, 8, "input").unwrap();
+        audit.record_dealloc(8, 0, 8).unwrap();
+        audit.record_transfer(8, 0);
+        audit.record_dealloc(8, 0, 8).unwrap();
+        assert_eq!(audit.outstanding().len(), 1);
+        audit.record_dealloc(8, 16, 8).unwrap();
+        assert_eq!(audit.check_leaks(), Ok(()));
+    }
+
+    #[test]
+    fn http_policy_refuses_connection_headers() {
+        let policy = http_policy();
+        let mut request = HttpRequest::get("https://api.example.com/");
+        request.headers.push(("Accept".to_string(), "text/plain".to_string()));
+        assert!(policy.check_request(&request).is_ok());
+        for (name, value) in [
+            ("host", "internal.example.com"),
+            ("Content-Length", "0"),
+            ("Transfer-Encoding", "chunked"),
+            ("connection", "upgrade"),
+            ("X-Header", "a\r\nHost: internal.example.com"),
+            ("Bad Name", "value"),
+            ("", "value"),
+        ] {
+            let mut request = request.clone();
+            request.headers.push((name.to_string(), value.to_string()));
+            assert!(policy.check_request(&request).is_err(), "{}: {}", name, value);
+        }
+    }
+
+    struct FixedBackend {
+        body: Vec<u8>,
+        max_response_body: Option<usize>,
+    }
+
+    impl HttpBackend for FixedBackend {
+        fn send(&mut self, _: &HttpTarget, _: &HttpRequest, max_response_body: usize)
+            -> Result<HttpResponse, HttpError>
+        {
+            self.max_response_body = Some(max_response_body);
+            Ok(HttpResponse { status: 200, headers: Vec::new(), body: self.body.clone() })
+        }
+    }
+
+    #[test]
+    fn http_request_passes_response_limit_to_backend() {
+        let policy = http_policy();
+        let request = WireRequest::from(HttpRequest::get("https://api.example.com/"));
+        let request = bincode::serialize(&request).unwrap();
+        for (len, accepted) in [(16, true), (17, false)] {
+            let mut backend = FixedBackend { body: vec![0; len], max_response_body: None };
+            let response = handle_http_request(
+                &mut backend, &policy, &request, &MessageLimits::unlimited()).unwrap();
+            let response: crate::http::WireResponse = bincode::deserialize(&response).unwrap();
+            assert_eq!(backend.max_response_body, Some(16));
+            assert_eq!(response.is_ok(), accepted);
+        }
+    }
+
+    #[test]
+    fn callback_queue_block_waits_for_pop() {
+        let queue = std::sync::Arc::new(CallbackQueue::new(1, OverflowPolicy::Block));
+        queue.push(1).unwrap();
+        let producer = {
+            let queue = queue.clone();
+            std::thread::spawn(move || queue.push(2).unwrap())
+        };
+        assert_eq!(queue.pop(), 1);
+        producer.join().unwrap();
+        assert_eq!(queue.pop(), 2);
+        assert!(queue.is_empty());
+        assert_eq!(queue.dropped(), 0);
+    }
+
+    #[test]
+    fn callback_queue_drop_oldest_counts_evictions() {
+        let queue = CallbackQueue::new(2, OverflowPolicy::DropOldest);
+        for item in 1..=5 {
+            queue.push(item).unwrap();
+        }
+        assert_eq!(queue.len(), 2);
+        assert_eq!(queue.dropped(), 3);
+        assert_eq!(queue.try_pop(), Some(4));
+        assert_eq!(queue.try_pop(), Some(5));
+        assert_eq!(queue.try_pop(), None);
+    }
+
+    #[test]
+    fn callback_queue_error_refuses_items_when_full() {
+        let queue = CallbackQueue::new(1, OverflowPolicy::Error);
+        queue.push(1).unwrap();
+        assert_eq!(queue.push(2), Err(PlugitinError::QueueFull { capacity: 1 }));
+        assert_eq!(queue.try_pop(), Some(1));
+        queue.push(3).unwrap();
+        assert_eq!(queue.dropped(), 0);
+    }
+
+    #[test]
+    fn callback_queue_survives_poisoning() {
+        let queue = std::sync::Arc::new(CallbackQueue::new(2, OverflowPolicy::Error));
+        queue.push(1).unwrap();
+        let poisoner = queue.clone();
+        let result = std::thread::spawn(move || {
+            let _state = poisoner.state.lock().unwrap();
+            panic!("poisoning the queue");
+        }).join();
+        assert!(result.is_err());
+        assert!(queue.state.is_poisoned());
+        queue.push(2).unwrap();
+        assert_eq!(queue.len(), 2);
+        assert_eq!(queue.pop(), 1);
+    }
+
+    #[test]
+    #[should_panic(expected = "capacity must be nonzero")]
+    fn callback_queue_rejects_zero_capacity() {
+        CallbackQueue::<u32>::new(0, OverflowPolicy::Block);
+    }
+
+    fn rate_limits(calls_per_second: Option<u32>) -> RateLimits {
+        RateLimits { calls_per_second, ..RateLimits::default() }
+    }
+
+    #[test]
+    fn rate_limiter_refills_over_time() {
+        let start = Instant::now();
+        let mut limiter = RateLimiter::new(rate_limits(Some(2)));
+        assert!(limiter.begin_call(start).is_ok());
+        assert!(limiter.begin_call(start).is_ok());
+        match limiter.begin_call(start) {
+            Err(PlugitinError::RateLimited { retry_after: Some(retry_after) }) => {
+                assert_eq!(retry_after, Duration::from_millis(500));
+            }
+            result => panic!("unexpected result {:?}", result),
+        }
+        assert_eq!(limiter.available_at(start), Some(start + Duration::from_millis(500)));
+        assert!(limiter.begin_call(start + Duration::from_millis(500)).is_ok());
+    }
+
+    #[test]
+    fn rate_limiter_with_zero_rate_refuses_everything() {
+        let start = Instant::now();
+        let mut limiter = RateLimiter::new(rate_limits(Some(0)));
+        assert_eq!(
+            limiter.begin_call(start),
+            Err(PlugitinError::RateLimited { retry_after: None }));
+        assert_eq!(limiter.available_at(start + Duration::from_secs(60)), None);
+    }
+
+    #[test]
+    fn rate_limiter_without_rate_allows_everything() {
+        let start = Instant::now();
+        let mut limiter = RateLimiter::new(rate_limits(None));
+        for _ in 0..100 {
+            assert!(limiter.begin_call(start).is_ok());
+        }
+        assert_eq!(limiter.available_at(start), Some(start));
+    }
+
+    #[test]
+    fn rate_limiter_budgets_host_calls_per_call() {
+        let start = Instant::now();
+        let mut limiter = RateLimiter::new(RateLimits {
+            calls_per_second: None,
+            host_calls_per_call: Some(2),
+            host_call_bytes_per_call: Some(100),
+        });
+        limiter.begin_call(start).unwrap();
+        assert!(limiter.host_call(10).is_ok());
+        assert!(limiter.host_call(10).is_ok());
+        assert!(limiter.host_call(10).is_err());
+
+        limiter.begin_call(start).unwrap();
+        assert!(limiter.host_call(100).is_ok());
+        assert!(limiter.host_call(1).is_err());
+    }
+
+    #[test]
+    fn circuit_breaker_quarantines_after_threshold() {
+        let start = Instant::now();
+        let cooldown = Duration::from_secs(10);
+        let mut breaker = CircuitBreaker::new(CircuitBreakerPolicy {
+            failure_threshold: 2,
+            cooldown,
+        });
+        breaker.record_failure(start);
+        assert!(breaker.allow(start).is_ok());
+        breaker.record_failure(start);
+        assert_eq!(
+            breaker.allow(start + Duration::from_secs(4)),
+            Err(PlugitinError::Quarantined { retry_after: Duration::from_secs(6) }));
+        assert!(breaker.is_open(start));
+        assert!(!breaker.is_open(start + cooldown));
+
+        // A single failure after the cooldown restarts the quarantine.
+        breaker.record_failure(start + cooldown);
+        assert!(breaker.is_open(start + cooldown));
+        breaker.record_success();
+        assert!(!breaker.is_open(start + cooldown));
+        breaker.record_failure(start + cooldown);
+        assert!(!breaker.is_open(start + cooldown));
+    }
+
+    #[test]
+    fn retry_policy_backs_off_exponentially() {
+        let policy = RetryPolicy {
+            max_attempts: 5,
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_millis(350),
+        };
+        assert_eq!(policy.backoff(1), Some(Duration::from_millis(100)));
+        assert_eq!(policy.backoff(2), Some(Duration::from_millis(200)));
+        assert_eq!(policy.backoff(3), Some(Duration::from_millis(350)));
+        assert_eq!(policy.backoff(4), Some(Duration::from_millis(350)));
+        assert_eq!(policy.backoff(5), None);
+        assert_eq!(RetryPolicy::default().backoff(1), None);
+
+        let policy = RetryPolicy { max_attempts: u32::MAX, ..policy };
+        assert_eq!(policy.backoff(100), Some(Duration::from_millis(350)));
+    }
+}
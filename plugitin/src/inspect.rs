@@ -0,0 +1,596 @@
+//! Static inspection of compiled plugin binaries.
+//!
+//! This module reads just enough of the WebAssembly binary format to check that a module
+//! implements the plugitin ABI described in the `abi` module, without instantiating it.
+//!
+//! # Features
+//! This module is only available if the **inspect** feature is enabled.
+
+use std::fmt;
+
+use crate::abi;
+use crate::manifest::{Manifest, MANIFEST_SECTION};
+use crate::PlugitinError;
+
+/// A WebAssembly value type, as it appears in function signatures.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ValType {
+    I32,
+    I64,
+    F32,
+    F64,
+    V128,
+    FuncRef,
+    ExternRef,
+}
+
+impl ValType {
+    fn from_byte(byte: u8) -> Option<Self> {
+        Some(match byte {
+            0x7F => ValType::I32,
+            0x7E => ValType::I64,
+            0x7D => ValType::F32,
+            0x7C => ValType::F64,
+            0x7B => ValType::V128,
+            0x70 => ValType::FuncRef,
+            0x6F => ValType::ExternRef,
+            _ => return None,
+        })
+    }
+}
+
+impl fmt::Display for ValType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            ValType::I32 => "i32",
+            ValType::I64 => "i64",
+            ValType::F32 => "f32",
+            ValType::F64 => "f64",
+            ValType::V128 => "v128",
+            ValType::FuncRef => "funcref",
+            ValType::ExternRef => "externref",
+        })
+    }
+}
+
+/// The parameter and result types of a function.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FuncType {
+    pub params: Vec<ValType>,
+    pub results: Vec<ValType>,
+}
+
+impl FuncType {
+    fn new(params: &[ValType], results: &[ValType]) -> Self {
+        Self { params: params.to_vec(), results: results.to_vec() }
+    }
+}
+
+impl fmt::Display for FuncType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let join = |types: &[ValType]| types.iter()
+            .map(|ty| ty.to_string())
+            .collect::<Vec<_>>()
+            .join(", ");
+        write!(f, "({}) -> ({})", join(&self.params), join(&self.results))
+    }
+}
+
+/// The kind of item named by an import or export.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ExternKind {
+    Func(FuncType),
+    Table,
+    Memory,
+    Global,
+    Tag,
+}
+
+impl fmt::Display for ExternKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ExternKind::Func(ty) => write!(f, "func {}", ty),
+            ExternKind::Table => f.write_str("table"),
+            ExternKind::Memory => f.write_str("memory"),
+            ExternKind::Global => f.write_str("global"),
+            ExternKind::Tag => f.write_str("tag"),
+        }
+    }
+}
+
+/// An item imported by a module.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Import {
+    pub module: String,
+    pub name: String,
+    pub kind: ExternKind,
+}
+
+/// An item exported by a module.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Export {
+    pub name: String,
+    pub kind: ExternKind,
+}
+
+/// A custom section of a module.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CustomSection {
+    pub name: String,
+    pub data: Vec<u8>,
+}
+
+/// The parts of a WebAssembly module relevant to plugitin.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ModuleInfo {
+    pub imports: Vec<Import>,
+    pub exports: Vec<Export>,
+    pub custom_sections: Vec<CustomSection>,
+}
+
+impl ModuleInfo {
+    /// Returns the plugin manifest embedded in the module, if it has one.
+    pub fn manifest(&self) -> Option<Result<Manifest, PlugitinError>> {
+        self.custom_sections
+            .iter()
+            .find(|section| section.name == MANIFEST_SECTION)
+            .map(|section| Manifest::parse(&section.data))
+    }
+
+    /// Returns the namespace of every plugin the module contains, as determined by its
+    /// init exports. The module's unnamespaced plugin, if any, has the empty namespace.
+    pub fn plugins(&self) -> Vec<&str> {
+        self.exports
+            .iter()
+            .filter_map(|export| {
+                let rest = export.name.strip_prefix(abi::INIT_EXPORT)?;
+                if rest.is_empty() {
+                    Some(rest)
+                } else {
+                    rest.strip_prefix(abi::NAMESPACE_SEPARATOR)
+                        .filter(|namespace| !namespace.is_empty())
+                }
+            })
+            .collect()
+    }
+}
+
+/// Error produced when a binary is not a well-formed WebAssembly module.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ParseError {
+    pub offset: usize,
+    pub message: String,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "malformed module at offset {:#x}: {}", self.offset, self.message)
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+/// A way in which a module fails to implement the plugitin ABI.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Problem {
+    MissingExport(String),
+    WrongExportKind { name: String, found: ExternKind },
+    WrongSignature { name: String, expected: FuncType, found: FuncType },
+    UnknownImport { module: String, name: String },
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Problem::MissingExport(name) => write!(f, "missing required export `{}`", name),
+            Problem::WrongExportKind { name, found } => write!(
+                f, "export `{}` should be a function but is a {}", name, found),
+            Problem::WrongSignature { name, expected, found } => write!(
+                f, "`{}` has signature {} but {} was expected", name, found, expected),
+            Problem::UnknownImport { module, name } => write!(
+                f, "imports `{}::{}`, which plugitin hosts do not provide", module, name),
+        }
+    }
+}
+
+/// Parses the imports, exports and custom sections of a WebAssembly module.
+pub fn parse_module(bytes: &[u8]) -> Result<ModuleInfo, ParseError> {
+    let mut reader = Reader { bytes, pos: 0 };
+    if reader.take(4)? != b"\0asm" {
+        return Err(reader.error("missing wasm magic number"));
+    }
+    if reader.take(4)? != [1, 0, 0, 0] {
+        return Err(reader.error("unsupported wasm version"));
+    }
+
+    let mut info = ModuleInfo::default();
+    let mut types = Vec::new();
+    // Type indices of every function in the function index space, imports first.
+    let mut funcs = Vec::new();
+    let mut raw_exports = Vec::new();
+
+    while !reader.is_empty() {
+        let id = reader.byte()?;
+        let len = reader.u32()? as usize;
+        let mut section = Reader { bytes: reader.take(len)?, pos: 0 };
+        let base = reader.pos - len;
+        let result = (|| -> Result<(), ParseError> {
+            match id {
+                0 => {
+                    let name = section.name()?;
+                    let data = section.take(section.bytes.len() - section.pos)?.to_vec();
+                    info.custom_sections.push(CustomSection { name, data });
+                }
+                1 => for _ in 0..section.u32()? {
+                    if section.byte()? != 0x60 {
+                        return Err(section.error("expected function type"));
+                    }
+                    let params = section.val_types()?;
+                    let results = section.val_types()?;
+                    types.push(FuncType { params, results });
+                },
+                2 => for _ in 0..section.u32()? {
+                    let module = section.name()?;
+                    let name = section.name()?;
+                    let kind = match section.byte()? {
+                        0x00 => {
+                            let ty = section.u32()?;
+                            funcs.push(ty);
+                            ExternKind::Func(section.func_type(&types, ty)?)
+                        }
+                        0x01 => {
+                            section.byte()?;
+                            section.limits()?;
+                            ExternKind::Table
+                        }
+                        0x02 => {
+                            section.limits()?;
+                            ExternKind::Memory
+                        }
+                        0x03 => {
+                            section.byte()?;
+                            section.byte()?;
+                            ExternKind::Global
+                        }
+                        0x04 => {
+                            section.byte()?;
+                            section.u32()?;
+                            ExternKind::Tag
+                        }
+                        _ => return Err(section.error("unknown import kind")),
+                    };
+                    info.imports.push(Import { module, name, kind });
+                },
+                3 => for _ in 0..section.u32()? {
+                    funcs.push(section.u32()?);
+                },
+                7 => for _ in 0..section.u32()? {
+                    let name = section.name()?;
+                    let kind = section.byte()?;
+                    let index = section.u32()?;
+                    raw_exports.push((name, kind, index, base + section.pos));
+                },
+                _ => {}
+            }
+            Ok(())
+        })();
+        result.map_err(|err| ParseError { offset: base + err.offset, message: err.message })?;
+    }
+
+    for (name, kind, index, offset) in raw_exports {
+        let kind = match kind {
+            0x00 => {
+                let error = || ParseError {
+                    offset,
+                    message: format!("export `{}` names an unknown function", name),
+                };
+                let ty = *funcs.get(index as usize).ok_or_else(error)?;
+                ExternKind::Func(types.get(ty as usize).cloned().ok_or_else(error)?)
+            }
+            0x01 => ExternKind::Table,
+            0x02 => ExternKind::Memory,
+            0x03 => ExternKind::Global,
+            0x04 => ExternKind::Tag,
+            _ => return Err(ParseError { offset, message: "unknown export kind".to_string() }),
+        };
+        info.exports.push(Export { name, kind });
+    }
+
+    Ok(info)
+}
+
+/// Checks a parsed module against the plugitin ABI, returning every problem found. An
+/// empty result means the module can be loaded by a plugitin host. Modules containing
+/// several plugins have the exports of each checked separately.
+pub fn check_module(info: &ModuleInfo) -> Vec<Problem> {
+    use ValType::{I32, I64};
+
+    let required = [
+        (abi::INIT_EXPORT, FuncType::new(&[], &[I32])),
+        (abi::DESTROY_EXPORT, FuncType::new(&[I32], &[])),
+        (abi::ALLOC_EXPORT, FuncType::new(&[I32, I32, I32], &[I32])),
+        (abi::DEALLOC_EXPORT, FuncType::new(&[I32, I32, I32, I32], &[])),
+        (abi::CLIENT_CALL_EXPORT, FuncType::new(&[I32, I64], &[I64])),
+    ];
+    let optional = [
+        (abi::ALLOC_UNINIT_EXPORT, FuncType::new(&[I32, I32, I32], &[I32])),
+        (abi::CLIENT_CALL_BATCH_EXPORT, FuncType::new(&[I32, I64], &[I64])),
+        (abi::ON_TIMER_EXPORT, FuncType::new(&[I32, I64], &[])),
+        (abi::ON_LOAD_EXPORT, FuncType::new(&[I32], &[])),
+        (abi::ON_IDLE_EXPORT, FuncType::new(&[I32], &[])),
+        (abi::ON_UNLOAD_EXPORT, FuncType::new(&[I32], &[])),
+        (abi::RELEASE_OUTPUT_EXPORT, FuncType::new(&[I32], &[])),
+        (abi::ALLOC_SHARED_DATA_EXPORT, FuncType::new(&[I32, I32], &[I32])),
+        (abi::SET_SHARED_DATA_EXPORT, FuncType::new(&[I32, I64], &[])),
+        (abi::SET_CALL_CONTEXT_EXPORT, FuncType::new(&[I32, I64], &[])),
+        (abi::BUFFER_SIZES_EXPORT, FuncType::new(&[I32], &[I64])),
+        (abi::LAST_ERROR_EXPORT, FuncType::new(&[], &[I32])),
+    ];
+    let imports = [
+        (abi::HOST_CALL_IMPORT, FuncType::new(&[I32, I64], &[I64])),
+        (abi::HOST_OUTPUT_IMPORT, FuncType::new(&[I32, I32, I64], &[])),
+        (abi::HOST_NOW_IMPORT, FuncType::new(&[I32], &[I64])),
+        (abi::HOST_RANDOM_IMPORT, FuncType::new(&[I32], &[I64])),
+        (abi::HOST_KV_GET_IMPORT, FuncType::new(&[I32, I64, I64], &[I64])),
+        (abi::HOST_KV_SET_IMPORT, FuncType::new(&[I32, I64, I64], &[])),
+        (abi::HOST_KV_DELETE_IMPORT, FuncType::new(&[I32, I64], &[I32])),
+        (abi::HOST_HTTP_IMPORT, FuncType::new(&[I32, I64], &[I64])),
+        (abi::HOST_SCHEDULE_IMPORT, FuncType::new(&[I32, I64, I64], &[])),
+    ];
+
+    let mut problems = Vec::new();
+
+    let mut plugins = info.plugins();
+    if plugins.is_empty() {
+        plugins.push("");
+    }
+    for namespace in plugins {
+        for (name, expected) in required.iter() {
+            let name = abi::namespaced_export(name, namespace);
+            match info.exports.iter().find(|export| export.name == name) {
+                Some(export) => check_func(&mut problems, &name, &export.kind, expected),
+                None => problems.push(Problem::MissingExport(name)),
+            }
+        }
+        for (name, expected) in optional.iter() {
+            let name = abi::namespaced_export(name, namespace);
+            if let Some(export) = info.exports.iter().find(|export| export.name == name) {
+                check_func(&mut problems, &name, &export.kind, expected);
+            }
+        }
+    }
+    for import in info.imports.iter() {
+        let known = imports.iter().find(|(name, _)| {
+            import.module == abi::IMPORT_MODULE && import.name == *name
+        });
+        match known {
+            Some((name, expected)) => check_func(&mut problems, name, &import.kind, expected),
+            None => problems.push(Problem::UnknownImport {
+                module: import.module.clone(),
+                name: import.name.clone(),
+            }),
+        }
+    }
+
+    if !info.exports.iter().any(|export| export.kind == ExternKind::Memory) {
+        problems.push(Problem::MissingExport("memory".to_string()));
+    }
+
+    problems
+}
+
+fn check_func(problems: &mut Vec<Problem>, name: &str, kind: &ExternKind, expected: &FuncType) {
+    match kind {
+        ExternKind::Func(found) if found == expected => {}
+        ExternKind::Func(found) => problems.push(Problem::WrongSignature {
+            name: name.to_string(),
+            expected: expected.clone(),
+            found: found.clone(),
+        }),
+        found => problems.push(Problem::WrongExportKind {
+            name: name.to_string(),
+            found: found.clone(),
+        }),
+    }
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn error(&self, message: &str) -> ParseError {
+        ParseError { offset: self.pos, message: message.to_string() }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.pos >= self.bytes.len()
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8], ParseError> {
+        match self.pos.checked_add(len) {
+            Some(end) if end <= self.bytes.len() => {
+                let slice = &self.bytes[self.pos..end];
+                self.pos = end;
+                Ok(slice)
+            }
+            _ => Err(self.error("unexpected end of data")),
+        }
+    }
+
+    fn byte(&mut self) -> Result<u8, ParseError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u64(&mut self) -> Result<u64, ParseError> {
+        let mut result = 0u64;
+        for shift in (0..64).step_by(7) {
+            let byte = self.byte()?;
+            result |= ((byte & 0x7F) as u64) << shift;
+            if byte & 0x80 == 0 {
+                return Ok(result);
+            }
+        }
+        Err(self.error("integer too long"))
+    }
+
+    fn u32(&mut self) -> Result<u32, ParseError> {
+        let value = self.u64()?;
+        if value > u32::MAX as u64 {
+            return Err(self.error("integer too large"));
+        }
+        Ok(value as u32)
+    }
+
+    fn name(&mut self) -> Result<String, ParseError> {
+        let len = self.u32()? as usize;
+        let bytes = self.take(len)?;
+        String::from_utf8(bytes.to_vec()).map_err(|_| self.error("name is not valid UTF-8"))
+    }
+
+    fn val_types(&mut self) -> Result<Vec<ValType>, ParseError> {
+        (0..self.u32()?)
+            .map(|_| {
+                let byte = self.byte()?;
+                ValType::from_byte(byte).ok_or_else(|| self.error("unknown value type"))
+            })
+            .collect()
+    }
+
+    fn limits(&mut self) -> Result<(), ParseError> {
+        let flags = self.byte()?;
+        self.u64()?;
+        if flags & 0x01 != 0 {
+            self.u64()?;
+        }
+        Ok(())
+    }
+
+    fn func_type(&self, types: &[FuncType], index: u32) -> Result<FuncType, ParseError> {
+        types.get(index as usize).cloned().ok_or_else(|| self.error("unknown type index"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const I32: u8 = 0x7f;
+    const I64: u8 = 0x7e;
+
+    // Hand-assembles a module. All counts and lengths used here are below 128, so every
+    // LEB128 value fits in a single byte.
+    fn section(id: u8, contents: &[u8]) -> Vec<u8> {
+        let mut bytes = vec![id, contents.len() as u8];
+        bytes.extend_from_slice(contents);
+        bytes
+    }
+
+    fn name(name: &str) -> Vec<u8> {
+        let mut bytes = vec![name.len() as u8];
+        bytes.extend_from_slice(name.as_bytes());
+        bytes
+    }
+
+    fn func_type(params: &[u8], results: &[u8]) -> Vec<u8> {
+        let mut bytes = vec![0x60, params.len() as u8];
+        bytes.extend_from_slice(params);
+        bytes.push(results.len() as u8);
+        bytes.extend_from_slice(results);
+        bytes
+    }
+
+    // A module implementing the required part of the ABI, with its client call export
+    // given client_call_params as parameters.
+    fn plugin_module(client_call_params: &[u8]) -> Vec<u8> {
+        let types = [
+            func_type(&[], &[I32]),
+            func_type(&[I32], &[]),
+            func_type(&[I32, I32, I32], &[I32]),
+            func_type(&[I32, I32, I32, I32], &[]),
+            func_type(&[I32, I64], &[I64]),
+            func_type(client_call_params, &[I64]),
+        ];
+        let mut type_section = vec![types.len() as u8];
+        types.iter().for_each(|ty| type_section.extend_from_slice(ty));
+
+        let mut import_section = vec![1];
+        import_section.extend(name("env"));
+        import_section.extend(name("plugitin_host_call"));
+        import_section.extend([0x00, 4]);
+
+        // Functions 1 to 5, after the imported function 0.
+        let function_section = [5, 0, 1, 2, 3, 5];
+        let memory_section = [1, 0x00, 1];
+
+        let exports = [
+            ("plugitin_init", 0x00, 1),
+            ("plugitin_destroy", 0x00, 2),
+            ("plugitin_alloc", 0x00, 3),
+            ("plugitin_dealloc", 0x00, 4),
+            ("plugitin_client_call", 0x00, 5),
+            ("memory", 0x02, 0),
+        ];
+        let mut export_section = vec![exports.len() as u8];
+        for (export, kind, index) in exports.iter() {
+            export_section.extend(name(export));
+            export_section.extend([*kind, *index]);
+        }
+
+        let mut custom_section = name("notes");
+        custom_section.extend_from_slice(b"hello");
+
+        let mut module = b"\0asm\x01\0\0\0".to_vec();
+        module.extend(section(1, &type_section));
+        module.extend(section(2, &import_section));
+        module.extend(section(3, &function_section));
+        module.extend(section(5, &memory_section));
+        module.extend(section(7, &export_section));
+        module.extend(section(0, &custom_section));
+        module
+    }
+
+    #[test]
+    fn parses_minimal_plugin() {
+        let info = parse_module(&plugin_module(&[I32, I64])).unwrap();
+        assert_eq!(info.imports, vec![Import {
+            module: "env".to_string(),
+            name: "plugitin_host_call".to_string(),
+            kind: ExternKind::Func(FuncType::new(&[ValType::I32, ValType::I64], &[ValType::I64])),
+        }]);
+        assert_eq!(info.exports.len(), 6);
+        assert_eq!(info.exports[1], Export {
+            name: "plugitin_destroy".to_string(),
+            kind: ExternKind::Func(FuncType::new(&[ValType::I32], &[])),
+        });
+        assert_eq!(info.exports[5].kind, ExternKind::Memory);
+        assert_eq!(info.custom_sections, vec![CustomSection {
+            name: "notes".to_string(),
+            data: b"hello".to_vec(),
+        }]);
+        assert_eq!(info.plugins(), vec![""]);
+        assert_eq!(info.manifest(), None);
+        assert_eq!(check_module(&info), Vec::new());
+    }
+
+    #[test]
+    fn reports_wrong_signatures() {
+        let info = parse_module(&plugin_module(&[I32, I32])).unwrap();
+        assert_eq!(check_module(&info), vec![Problem::WrongSignature {
+            name: "plugitin_client_call".to_string(),
+            expected: FuncType::new(&[ValType::I32, ValType::I64], &[ValType::I64]),
+            found: FuncType::new(&[ValType::I32, ValType::I32], &[ValType::I64]),
+        }]);
+    }
+
+    #[test]
+    fn rejects_malformed_modules() {
+        assert!(parse_module(b"\0wasm\x01\0\0\0").is_err());
+        assert!(parse_module(b"\0asm\x02\0\0\0").is_err());
+
+        let module = plugin_module(&[I32, I64]);
+        let err = parse_module(&module[..module.len() - 2]).unwrap_err();
+        assert!(err.offset <= module.len(), "{}", err);
+    }
+}
@@ -0,0 +1,134 @@
+//! Plugin manifests, which describe a plugin without having to instantiate it.
+//!
+//! A manifest is embedded in the [`MANIFEST_SECTION`] custom section of a plugin module by
+//! the `plugin_manifest!` macro, so that hosts and registries can index large directories
+//! of plugins by reading the section, for example with the `inspect` module, instead of
+//! instantiating each one.
+//!
+//! The section holds UTF-8 text with one `key=value` entry per line. `name` and `version`
+//! are required and `capabilities` is an optional comma-separated list. Unknown keys are
+//! ignored so that later versions of plugitin can add entries.
+
+use crate::PlugitinError;
+
+/// Name of the custom section which holds the plugin manifest.
+pub const MANIFEST_SECTION: &str = "plugitin.manifest";
+
+/// Name, version and capabilities of a plugin, as declared with `plugin_manifest!`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Manifest {
+    pub name: String,
+    pub version: String,
+    /// Host capabilities the plugin uses, such as `http` or `kv`.
+    pub capabilities: Vec<String>,
+}
+
+impl Manifest {
+    /// Parses the contents of a manifest section.
+    ///
+    /// ```
+    /// use plugitin::manifest::Manifest;
+    ///
+    /// let manifest = Manifest::parse(b"name=greeter\nversion=1.2.0\ncapabilities=kv,http,\n")
+    ///     .unwrap();
+    /// assert_eq!(manifest.name, "greeter");
+    /// assert_eq!(manifest.version, "1.2.0");
+    /// assert_eq!(manifest.capabilities, vec!["kv", "http"]);
+    /// ```
+    pub fn parse(data: &[u8]) -> Result<Manifest, PlugitinError> {
+        let text = std::str::from_utf8(data)
+            .map_err(|err| PlugitinError::InvalidManifest(err.to_string()))?;
+
+        let mut name = None;
+        let mut version = None;
+        let mut capabilities = None;
+        for line in text.lines().filter(|line| !line.is_empty()) {
+            let (key, value) = split_entry(line).ok_or_else(|| {
+                PlugitinError::InvalidManifest(format!("malformed entry `{}`", line))
+            })?;
+            let field = match key {
+                "name" => &mut name,
+                "version" => &mut version,
+                "capabilities" => &mut capabilities,
+                _ => continue,
+            };
+            // Linkers concatenate sections of the same name, so a duplicate key usually
+            // means several crates in the module declared a manifest.
+            if field.replace(value).is_some() {
+                return Err(PlugitinError::InvalidManifest(format!("duplicate key `{}`", key)));
+            }
+        }
+
+        let required = |value: Option<&str>, key: &str| {
+            value.map(str::to_string).ok_or_else(|| {
+                PlugitinError::InvalidManifest(format!("missing key `{}`", key))
+            })
+        };
+        Ok(Manifest {
+            name: required(name, "name")?,
+            version: required(version, "version")?,
+            capabilities: capabilities.unwrap_or("")
+                .split(',')
+                .filter(|capability| !capability.is_empty())
+                .map(str::to_string)
+                .collect(),
+        })
+    }
+}
+
+fn split_entry(line: &str) -> Option<(&str, &str)> {
+    let separator = line.find('=')?;
+    Some((&line[..separator], &line[separator + 1..]))
+}
+
+// Copies manifest text into a fixed-size array, which is what a link section must hold.
+#[doc(hidden)]
+pub const fn manifest_bytes<const N: usize>(text: &str) -> [u8; N] {
+    let bytes = text.as_bytes();
+    let mut array = [0u8; N];
+    let mut index = 0;
+    while index < N {
+        array[index] = bytes[index];
+        index += 1;
+    }
+    array
+}
+
+/// Embeds a plugin manifest in the module's [`MANIFEST_SECTION`] custom section. Takes
+/// string literals, or macros such as `env!` which expand to them, for the `name` and
+/// `version`, followed by an optional list of `capabilities`. Declare a single manifest
+/// per module. The section is only emitted for `wasm32` targets; elsewhere, such as when
+/// the plugin's tests run natively, the manifest is compiled but not placed in a section.
+///
+/// # Examples
+///
+/// ```
+/// use plugitin::plugin_manifest;
+///
+/// plugin_manifest!(
+///     name = "greeter",
+///     version = env!("CARGO_PKG_VERSION"),
+///     capabilities = ["kv", "http"],
+/// );
+/// ```
+#[macro_export]
+macro_rules! plugin_manifest {
+    (
+        name = $name:expr,
+        version = $version:expr
+        $(, capabilities = [$($capability:expr),* $(,)?])?
+        $(,)?
+    ) => {
+        const _: () = {
+            const TEXT: &str = concat!(
+                "name=", $name, "\n",
+                "version=", $version, "\n",
+                $("capabilities=", $($capability, ",",)* "\n",)?
+            );
+
+            #[used]
+            #[cfg_attr(target_arch = "wasm32", link_section = "plugitin.manifest")]
+            static MANIFEST: [u8; TEXT.len()] = $crate::manifest::manifest_bytes(TEXT);
+        };
+    };
+}
@@ -0,0 +1,87 @@
+//! Utilities for testing plugin interfaces.
+//!
+//! Messages only cross the plugin boundary at runtime, so a message type which does not
+//! survive serialization, for example because of a custom `Serialize` implementation which
+//! disagrees with its `Deserialize` implementation, is easy to miss until a plugin is
+//! deployed. [`assert_roundtrip`] catches such mistakes in ordinary unit tests. It pairs
+//! well with a property testing crate such as `proptest`, which can generate the values to
+//! check for each of a plugin's four message types.
+//!
+//! # Features
+//! This module is only available if the **testing** feature is enabled.
+
+use std::fmt::Debug;
+
+use bincode::{serialize_into, serialized_size};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use crate::abi::{split_envelope, write_envelope_header, ENVELOPE_HEADER_LEN};
+use crate::codec::deserialize_message;
+use crate::PlugitinError;
+
+/// Serializes `value` exactly as one side of the plugin boundary does and deserializes it
+/// as the other side does, returning the value the receiver would see.
+pub fn roundtrip<T>(value: &T) -> Result<T, PlugitinError>
+    where T: Serialize + DeserializeOwned
+{
+    let message = serialize_message(value, 0)?;
+    deserialize_message(&message, None).map_err(PlugitinError::DeserializeFailed)
+}
+
+/// Like `roundtrip`, but wraps the message in a versioned envelope for `version`, as
+/// plugins which set `Plugin::INTERFACE_VERSION` do.
+pub fn roundtrip_versioned<T>(value: &T, version: u32) -> Result<T, PlugitinError>
+    where T: Serialize + DeserializeOwned
+{
+    let mut message = serialize_message(value, ENVELOPE_HEADER_LEN)?;
+    write_envelope_header(&mut message, version);
+    let (found, payload) = split_envelope(&message).ok_or(PlugitinError::InvalidEnvelope)?;
+    if found != version {
+        return Err(PlugitinError::UnsupportedVersion { found, expected: version });
+    }
+    deserialize_message(payload, None).map_err(PlugitinError::DeserializeFailed)
+}
+
+/// Asserts that `value` arrives unchanged on the other side of the plugin boundary, both
+/// with and without a versioned envelope.
+///
+/// # Panics
+/// Panics if the value cannot be serialized or deserialized, or deserializes to a value
+/// which is not equal to the original.
+///
+/// # Examples
+///
+/// ```
+/// use std::collections::BTreeMap;
+/// use plugitin::testing::assert_roundtrip;
+///
+/// assert_roundtrip(&("Ferris".to_string(), vec![1u32, 2, 3]));
+/// assert_roundtrip(&Some(BTreeMap::from([(1u8, -1.5f64)])));
+/// ```
+pub fn assert_roundtrip<T>(value: &T)
+    where T: Serialize + DeserializeOwned + PartialEq + Debug
+{
+    match roundtrip(value) {
+        Ok(received) => assert_eq!(&received, value, "message changed crossing the boundary"),
+        Err(err) => panic!("{:?} failed to cross the boundary: {}", value, err),
+    }
+    match roundtrip_versioned(value, 1) {
+        Ok(received) => assert_eq!(
+            &received, value, "versioned message changed crossing the boundary"),
+        Err(err) => panic!("{:?} failed to cross the boundary versioned: {}", value, err),
+    }
+}
+
+// Serializes a message into a buffer of exactly the right size, leaving `header_len` bytes
+// free at the start, the way both sides of the boundary do.
+fn serialize_message<T: Serialize>(value: &T, header_len: usize)
+    -> Result<Vec<u8>, PlugitinError>
+{
+    let payload_len = serialized_size(value)
+        .map_err(|err| PlugitinError::SerializeFailed(err.to_string()))?;
+    let mut message = vec![0u8; header_len + payload_len as usize];
+    serialize_into(&mut message[header_len..], value)
+        .map_err(|err| PlugitinError::SerializeFailed(err.to_string()))?;
+    Ok(message)
+}